@@ -1,14 +1,15 @@
 use std::collections::HashMap;
 
 use proc_macro::TokenStream;
-use proc_macro2::Ident;
+use proc_macro2::{Group, Ident, Span, TokenTree};
 use quote::{ToTokens, format_ident, quote};
-use syn::parse::Parse;
+use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
-use syn::token::Comma;
+use syn::token::{Comma, Paren};
 use syn::{
-    Attribute, GenericParam, Generics, Index, Meta, Path, PredicateType, Token, Type, TypeParam,
-    TypePath, WhereClause, WherePredicate,
+    Attribute, Expr, GenericArgument, GenericParam, Generics, Index, Lifetime, LitBool, LitStr,
+    Member, Meta, Path, PathArguments, PredicateType, Token, Type, TypeParam, TypeParamBound,
+    TypePath, WhereClause, WherePredicate, parenthesized,
 };
 
 /// A custom derive implementation for `#[derive(With)]`
@@ -73,6 +74,133 @@ pub fn derive(input: TokenStream) -> TokenStream {
     result.into()
 }
 
+/// Update several fields in one expression through the with-constructors, like
+/// `with!(foo, a: 1, b: "b", c)` for `foo.with_a(1).with_b("b").with_c(c)`
+///
+/// ```rust
+/// use derive_with::{With, with};
+///
+/// #[derive(With, Default)]
+/// pub struct Foo {
+///     pub a: i32,
+///     pub b: String,
+/// }
+///
+/// #[derive(With, Default)]
+/// pub struct Bar (i32, String);
+///
+/// fn test_struct() {
+///     let b = "b";
+///     let foo = with!(Foo::default(), a: 1, b);
+///     assert_eq!(foo.a, 1);
+///     assert_eq!(foo.b, "b".to_string());
+///
+///     let bar = with!(Bar::default(), 0: 1, 1: "b");
+///     assert_eq!(bar.0, 1);
+///     assert_eq!(bar.1, "b".to_string());
+/// }
+/// ```
+#[proc_macro]
+pub fn with(input: TokenStream) -> TokenStream {
+    let with_input = syn::parse_macro_input!(input as WithInput);
+    let value = with_input.value;
+    // The value is the receiver of the with-constructors, like `(*boxed).with_a(1)`
+    let mut result = if with_input.fields.is_empty() {
+        value.to_token_stream()
+    } else {
+        quote! { (#value) }
+    };
+    for (member, value) in with_input.fields {
+        // Unknown fields are reported at their span, as the with-constructor is not found
+        let constructor_name = match &member {
+            Member::Named(field_name) => format_ident!("with_{}", field_name),
+            Member::Unnamed(index) => Ident::new(&format!("with_{}", index.index), index.span),
+        };
+        result = quote! { #result.#constructor_name(#value) };
+    }
+    result.into()
+}
+
+/// Input of the `with!` macro, like `foo, a: 1, b: "b", c`
+struct WithInput {
+    value: Expr,
+    /// Updated fields with their values, which default to the variable of the same name
+    fields: Vec<(Member, Expr)>,
+}
+
+impl Parse for WithInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let value = input.parse()?;
+        let mut fields = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let member = input.parse::<Member>()?;
+            let field_value = if input.peek(Token![:]) {
+                input.parse::<Token![:]>()?;
+                input.parse()?
+            } else {
+                match &member {
+                    Member::Named(field_name) => syn::parse_quote! { #field_name },
+                    Member::Unnamed(index) => {
+                        return Err(syn::Error::new(
+                            index.span,
+                            "tuple struct fields need a value, like `0: value`",
+                        ));
+                    }
+                }
+            };
+            fields.push((member, field_value));
+        }
+        Ok(WithInput { value, fields })
+    }
+}
+
+/// Declare the traits selecting the field to update by its marker type, which are implemented
+/// by `#[derive(With)]` with `#[with(selectors)]`
+///
+/// ```rust
+/// use derive_with::{With, with_selectors};
+///
+/// with_selectors!(pub);
+///
+/// #[derive(With, Default)]
+/// #[with(selectors)]
+/// pub struct Foo {
+///     pub a: i32,
+///     pub b: String,
+/// }
+///
+/// fn test_struct() {
+///     let foo = Foo::default().with::<foo_fields::a>(1);
+///     assert_eq!(foo.a, 1);
+/// }
+/// ```
+#[proc_macro]
+pub fn with_selectors(input: TokenStream) -> TokenStream {
+    let vis: syn::Visibility = syn::parse(input).expect("Couldn't parse visibility");
+    let result = quote! {
+        #vis trait With<F> {
+            type Value;
+            fn with_field(self, value: Self::Value) -> Self;
+        }
+
+        #vis trait WithExt: Sized {
+            fn with<F>(self, value: <Self as With<F>>::Value) -> Self
+            where
+                Self: With<F>,
+            {
+                <Self as With<F>>::with_field(self, value)
+            }
+        }
+
+        impl<T> WithExt for T {}
+    };
+    result.into()
+}
+
 fn with_for_struct(ast: &syn::DeriveInput, fields: &syn::Fields) -> proc_macro2::TokenStream {
     match *fields {
         syn::Fields::Named(ref fields) => with_constructor_for_named(ast, &fields.named),
@@ -86,120 +214,130 @@ fn with_constructor_for_named(
     fields: &Punctuated<syn::Field, Token![,]>,
 ) -> proc_macro2::TokenStream {
     let name = &ast.ident;
-    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
     let generics_map = index_generics(&ast.generics);
     let where_predicate_map = index_where_predicates(&ast.generics.where_clause);
-    let with_args = parse_with_args::<Ident>(&ast.attrs);
+    let field_names: Vec<_> = fields
+        .iter()
+        .filter_map(|field| field.ident.as_ref())
+        .collect();
+    let with_args = parse_with_args::<Ident>(&ast.attrs, &field_names);
     let field_count = fields.len();
 
+    let field_enum_name = format_ident!("{}Field", name);
+    let tracker = find_tracker(fields);
+
     let mut constructors = quote!();
+    let mut selected_fields = Vec::new();
     for field in fields {
         let field_name = field.ident.as_ref().unwrap();
-        if !contains_field(&with_args, field_name) {
+        let options = parse_field_options(&field.attrs);
+        if !contains_field(&with_args, field_name) || options.tracker {
             continue;
         }
+        let member = Member::from(field_name.clone());
+        let track = generate_track(&tracker, &field_enum_name, &member);
+        let selected_field = SelectedField {
+            field,
+            member,
+            arg_name: field_name.clone(),
+            options,
+            track,
+        };
         let field_type = &field.ty;
+        let field_options = &selected_field.options;
+        let conversion = Conversion::of(field_type, field_options, &with_args.exact_types);
         let constructor_name = format_ident!("with_{}", field_name);
 
-        // Check the type of the field
-        let constructor = match field_type {
-            // For simple path types
-            Type::Path(type_path) => {
-                // Check if the type matches some generic parameter
-                match generics_map.get(&type_path.path).cloned() {
-                    // If the type is not generic, just use the Into trait to derive the method
-                    None => generate_constructor_for_named(
-                        &constructor_name,
-                        field_name,
-                        field_type,
-                        field_count,
-                    ),
-                    // If the type is generic, allow to switch types
-                    Some(mut generic) => {
-                        let new_generic = format_ident!("W{}", generic.ident);
-                        // Update the generic ident for the new one, so that it doesn't conflict with the existing
-                        generic.ident = new_generic.clone();
-
-                        // Determine the new generics, which are the existing generics
-                        let mut new_generic_params = Vec::new();
-                        for param in &ast.generics.params {
-                            new_generic_params.push(match param {
-                                // Except for the generic parameter that matches the field type
-                                GenericParam::Type(type_param)
-                                    if type_path.path.is_ident(&type_param.ident) =>
-                                {
-                                    // That must be replaced with the new generic ident
-                                    new_generic.to_token_stream()
-                                }
-                                GenericParam::Type(type_param) => {
-                                    type_param.ident.to_token_stream()
-                                }
-                                GenericParam::Lifetime(lifetime_param) => {
-                                    lifetime_param.lifetime.to_token_stream()
-                                }
-                                GenericParam::Const(const_param) => {
-                                    const_param.ident.to_token_stream()
-                                }
-                            });
-                        }
-
-                        // Compute the new field values, as we can't deconstruct when switching types
-                        let mut other_fields = Vec::new();
-                        for other_field in fields {
-                            let other_field_name = other_field.ident.as_ref().unwrap();
-                            if other_field_name != field_name {
-                                other_fields
-                                    .push(quote! { #other_field_name: self.#other_field_name });
-                            } else {
-                                other_fields.push(quote! { #field_name });
-                            }
-                        }
-
-                        // Retrieve the where predicate affecting this field, if any
-                        let where_clause = where_predicate_map.get(&type_path.path).cloned().map(
-                            |mut predicate| {
-                                // And update the bounded type to the new generic ident
-                                predicate.bounded_ty = Type::Path(TypePath {
-                                    qself: None,
-                                    path: Path::from(new_generic.clone()),
-                                });
-                                quote! { where #predicate }
-                            },
-                        );
-
-                        quote! {
-                            pub fn #constructor_name <#generic> (self, #field_name: #new_generic)
-                            -> #name < #(#new_generic_params),* >
-                            #where_clause
-                            {
-                                #name {
-                                    #(#other_fields),*
-                                }
-                            }
-                        }
-                    }
-                }
+        // Check if the field type allows to switch some generic parameters
+        // Remote structs may have private fields, which don't allow to switch types,
+        // and nested fields are updated by closures keeping their type
+        let keep_type = with_args.remote.is_some()
+            || field_options.nested
+            || field_options.keep_type.unwrap_or(with_args.keep_type);
+        let switch = if keep_type {
+            None
+        } else {
+            generic_switch(
+                &ast.generics,
+                fields,
+                field,
+                &generics_map,
+                &where_predicate_map,
+            )
+        };
+        let constructor = match switch {
+            // If the field is nested, update it with a closure
+            None if field_options.nested => {
+                generate_nested_constructor(&constructor_name, &selected_field, field_name)
             }
-            // For every other field type, just use the Into trait to derive the method
-            _ => generate_constructor_for_named(
+            // If the type is not generic, just use the Into trait to derive the method
+            None => generate_constructor_for_named(
                 &constructor_name,
                 field_name,
-                field_type,
+                &conversion,
                 field_count,
+                &selected_field.track,
+                with_args.remote.is_some(),
             ),
+            // If the type is generic, allow to switch types
+            Some(switch) => {
+                // Compute the new field values, as we can't deconstruct when switching types
+                let mut other_fields = Vec::new();
+                for other_field in fields {
+                    let other_field_name = other_field.ident.as_ref().unwrap();
+                    if other_field_name != field_name {
+                        let value = switch
+                            .other_field_value(other_field, quote! { self.#other_field_name });
+                        other_fields.push(quote! { #other_field_name: #value });
+                    } else {
+                        other_fields.push(quote! { #field_name });
+                    }
+                }
+
+                let GenericSwitch {
+                    params,
+                    ty_generics: new_ty_generics,
+                    arg_type,
+                    where_clause: new_where_clause,
+                    ..
+                } = switch;
+                let track = &selected_field.track;
+                let receiver = self_receiver(track);
+                quote! {
+                    pub fn #constructor_name <#(#params),*> (#receiver, #field_name: #arg_type)
+                    -> #name < #(#new_ty_generics),* >
+                    #new_where_clause
+                    {
+                        #track
+                        #name {
+                            #(#other_fields),*
+                        }
+                    }
+                }
+            }
         };
 
         constructors = quote! {
             #constructors
             #constructor
         };
-    }
-    quote! {
-        #[automatically_derived]
-        impl #impl_generics #name #ty_generics #where_clause {
+        let helpers = generate_field_helpers(&selected_field, &with_args, &conversion);
+        constructors = quote! {
             #constructors
-        }
+            #helpers
+        };
+        selected_fields.push(selected_field);
     }
+
+    generate_struct_items(
+        ast,
+        fields,
+        &selected_fields,
+        &with_args,
+        &tracker,
+        constructors,
+        true,
+    )
 }
 
 fn with_constructor_for_unnamed(
@@ -207,136 +345,804 @@ fn with_constructor_for_unnamed(
     fields: &Punctuated<syn::Field, Token![,]>,
 ) -> proc_macro2::TokenStream {
     let name = &ast.ident;
-    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
     let generics_map = index_generics(&ast.generics);
     let where_predicate_map = index_where_predicates(&ast.generics.where_clause);
-    let with_args = parse_with_args::<Index>(&ast.attrs);
+    let with_args = parse_with_args::<Index>(&ast.attrs, &[]);
+
+    let field_enum_name = format_ident!("{}Field", name);
+    let tracker = find_tracker(fields);
 
     let mut constructors = quote!();
+    let mut selected_fields = Vec::new();
     for (index, field) in fields.iter().enumerate() {
         let index = syn::Index::from(index);
-        if !contains_field(&with_args, &index) {
+        let options = parse_field_options(&field.attrs);
+        if !contains_field(&with_args, &index) || options.tracker {
             continue;
         }
-        let field_type = &field.ty;
         let field_name = format_ident!("field_{}", index);
+        let member = Member::from(index.clone());
+        let track = generate_track(&tracker, &field_enum_name, &member);
+        let selected_field = SelectedField {
+            field,
+            member,
+            arg_name: field_name.clone(),
+            options,
+            track,
+        };
+        let field_type = &field.ty;
+        let field_options = &selected_field.options;
+        let conversion = Conversion::of(field_type, field_options, &with_args.exact_types);
         let constructor_name = format_ident!("with_{}", index);
 
-        // Check the type of the field
-        let constructor = match field_type {
-            // For simple path types
-            Type::Path(type_path) => {
-                // Check if the type matches some generic parameter
-                match generics_map.get(&type_path.path).cloned() {
-                    // If the type is not generic, just use the Into trait to derive the method
-                    None => generate_constructor_for_unnamed(
-                        &constructor_name,
-                        index,
-                        &field_name,
-                        field_type,
-                    ),
-
-                    // If the type is generic, allow to switch types
-                    Some(mut generic) => {
-                        let new_generic = format_ident!("W{}", generic.ident);
-                        // Update the generic ident for the new one, so that it doesn't conflict with the existing
-                        generic.ident = new_generic.clone();
-
-                        // Determine the new generics, which are the existing generics
-                        let mut new_generic_params = Vec::new();
-                        for param in &ast.generics.params {
-                            new_generic_params.push(match param {
-                                // Except for the generic parameter that matches the field type
-                                GenericParam::Type(type_param)
-                                    if type_path.path.is_ident(&type_param.ident) =>
-                                {
-                                    // That must be replaced with the new generic ident
-                                    new_generic.to_token_stream()
-                                }
-                                GenericParam::Type(type_param) => {
-                                    type_param.ident.to_token_stream()
-                                }
-                                GenericParam::Lifetime(lifetime_param) => {
-                                    lifetime_param.lifetime.to_token_stream()
-                                }
-                                GenericParam::Const(const_param) => {
-                                    const_param.ident.to_token_stream()
-                                }
-                            });
-                        }
-
-                        // Compute the new field values
-                        let mut other_fields = Vec::new();
-                        for (other_index, _) in fields.iter().enumerate() {
-                            let other_index = syn::Index::from(other_index);
-                            if other_index != index {
-                                other_fields.push(quote! { self.#other_index });
-                            } else {
-                                other_fields.push(quote! { #field_name });
-                            }
-                        }
-
-                        // Retrieve the where predicate affecting this field, if any
-                        let where_clause = where_predicate_map.get(&type_path.path).cloned().map(
-                            |mut predicate| {
-                                // And update the bounded type to the new generic ident
-                                predicate.bounded_ty = Type::Path(TypePath {
-                                    qself: None,
-                                    path: Path::from(new_generic.clone()),
-                                });
-                                quote! { where #predicate }
-                            },
+        // Check if the field type allows to switch some generic parameters
+        // Remote structs may have private fields, which don't allow to switch types,
+        // and nested fields are updated by closures keeping their type
+        let keep_type = with_args.remote.is_some()
+            || field_options.nested
+            || field_options.keep_type.unwrap_or(with_args.keep_type);
+        let switch = if keep_type {
+            None
+        } else {
+            generic_switch(
+                &ast.generics,
+                fields,
+                field,
+                &generics_map,
+                &where_predicate_map,
+            )
+        };
+        let constructor = match switch {
+            // If the field is nested, update it with a closure
+            None if field_options.nested => {
+                generate_nested_constructor(&constructor_name, &selected_field, &field_name)
+            }
+            // If the type is not generic, just use the Into trait to derive the method
+            None => generate_constructor_for_unnamed(
+                &constructor_name,
+                index.clone(),
+                &field_name,
+                &conversion,
+                &selected_field.track,
+            ),
+            // If the type is generic, allow to switch types
+            Some(switch) => {
+                // Compute the new field values
+                let mut other_fields = Vec::new();
+                for (other_index, other_field) in fields.iter().enumerate() {
+                    let other_index = syn::Index::from(other_index);
+                    if other_index != index {
+                        other_fields.push(
+                            switch.other_field_value(other_field, quote! { self.#other_index }),
                         );
+                    } else {
+                        other_fields.push(quote! { #field_name });
+                    }
+                }
 
-                        quote! {
-                            pub fn #constructor_name <#generic> (self, #field_name: #new_generic)
-                            -> #name < #(#new_generic_params),* >
-                            #where_clause
-                            {
-                                #name ( #(#other_fields),* )
-                            }
-                        }
+                let GenericSwitch {
+                    params,
+                    ty_generics: new_ty_generics,
+                    arg_type,
+                    where_clause: new_where_clause,
+                    ..
+                } = switch;
+                let track = &selected_field.track;
+                let receiver = self_receiver(track);
+                quote! {
+                    pub fn #constructor_name <#(#params),*> (#receiver, #field_name: #arg_type)
+                    -> #name < #(#new_ty_generics),* >
+                    #new_where_clause
+                    {
+                        #track
+                        #name ( #(#other_fields),* )
                     }
                 }
             }
-            // For every other field type, just use the Into trait to derive the method
-            _ => {
-                generate_constructor_for_unnamed(&constructor_name, index, &field_name, field_type)
-            }
         };
 
         constructors = quote! {
             #constructors
             #constructor
         };
+        let helpers = generate_field_helpers(&selected_field, &with_args, &conversion);
+        constructors = quote! {
+            #constructors
+            #helpers
+        };
+        selected_fields.push(selected_field);
     }
-    quote! {
-        #[automatically_derived]
-        impl #impl_generics #name #ty_generics #where_clause {
+
+    generate_struct_items(
+        ast,
+        fields,
+        &selected_fields,
+        &with_args,
+        &tracker,
+        constructors,
+        false,
+    )
+}
+
+/// Generate the items shared by named and tuple structs from the selected fields, with the
+/// impl of the given with-constructors
+fn generate_struct_items<T>(
+    ast: &syn::DeriveInput,
+    fields: &Punctuated<syn::Field, Token![,]>,
+    selected_fields: &[SelectedField],
+    with_args: &WithArgs<T>,
+    tracker: &Option<Member>,
+    mut constructors: proc_macro2::TokenStream,
+    named: bool,
+) -> proc_macro2::TokenStream {
+    let name = &ast.ident;
+    let generics_map = index_generics(&ast.generics);
+    let where_predicate_map = index_where_predicates(&ast.generics.where_clause);
+    let field_enum_name = format_ident!("{}Field", name);
+
+    // Generate typestate transitions, which only switch a generic parameter
+    for (transition_name, param) in &with_args.transitions {
+        if with_args.remote.is_some() {
+            panic!("typestate transitions are not supported on remote structs");
+        }
+        let constructor_name = format_ident!("with_{}", transition_name);
+        if let Some(selected_field) = selected_fields.iter().find(|selected_field| {
+            field_method_name("with_", &selected_field.member, "") == constructor_name
+        }) {
+            panic!(
+                "`{constructor_name}` of the transition conflicts with the with-constructor of field `{}`, rename it like `#[with(to_{transition_name} = {param})]`",
+                field_name_str(&selected_field.member)
+            );
+        }
+        // Transitions only rebuild the markers, so no other field can use the parameter
+        if let Some((index, field)) = fields
+            .iter()
+            .enumerate()
+            .find(|(_, field)| !is_phantom_data(&field.ty) && type_mentions(&field.ty, param))
+        {
+            let field_name = match &field.ident {
+                Some(field_name) => field_name.to_string(),
+                None => index.to_string(),
+            };
+            panic!(
+                "`{param}` of the transition `{transition_name}` must only be used by `PhantomData` fields, but field `{field_name}` uses it"
+            );
+        }
+        let switch = generics_map
+            .get(&Path::from(param.clone()))
+            .map(|generic| switch_type_param(&ast.generics, generic, &where_predicate_map))
+            .unwrap_or_else(|| panic!("`{param}` is not a generic type parameter"));
+        let field_values = fields.iter().enumerate().map(|(index, field)| {
+            let member = match &field.ident {
+                Some(field_name) => Member::from(field_name.clone()),
+                None => Member::from(index),
+            };
+            let value = switch.other_field_value(field, quote! { self.#member });
+            if named {
+                quote! { #member: #value }
+            } else {
+                value
+            }
+        });
+        let new_value = if named {
+            quote! { #name { #(#field_values),* } }
+        } else {
+            quote! { #name ( #(#field_values),* ) }
+        };
+
+        let GenericSwitch {
+            params,
+            ty_generics: new_ty_generics,
+            where_clause: new_where_clause,
+            ..
+        } = &switch;
+        constructors = quote! {
+            #constructors
+            pub fn #constructor_name <#(#params),*> (self) -> #name < #(#new_ty_generics),* >
+            #new_where_clause
+            {
+                #new_value
+            }
+        };
+    }
+
+    // Generate the patch struct and the method applying it
+    let mut patch = quote!();
+    if with_args.patch || with_args.diff {
+        let (apply, patch_items) = generate_patch(ast, selected_fields, with_args, named);
+        constructors = quote! {
+            #constructors
+            #apply
+        };
+        patch = patch_items;
+    }
+
+    // Generate the field enum, with the methods reading the tracked changes
+    let mut field_enum = quote!();
+    if with_args.field_enum || tracker.is_some() {
+        field_enum = generate_field_enum(ast, &field_enum_name, selected_fields);
+    }
+    if let Some(tracker) = tracker {
+        let tracker_methods = generate_tracker_methods(&field_enum_name, tracker);
+        constructors = quote! {
+            #constructors
+            #tracker_methods
+        };
+    }
+
+    // Generate the field value enum and the method setting a field value
+    if with_args.field_enum {
+        let (with_field_value, field_value_items) =
+            generate_field_value(ast, &field_enum_name, selected_fields, with_args);
+        constructors = quote! {
+            #constructors
+            #with_field_value
+        };
+        field_enum = quote! {
+            #field_enum
+            #field_value_items
+        };
+    }
+
+    // Generate the field names and the method setting a field from its name and any value
+    let reflection = generate_reflection(selected_fields, with_args);
+    constructors = quote! {
+        #constructors
+        #reflection
+    };
+
+    // Generate the methods setting a field from its name and string value
+    let mut with_str_error = quote!();
+    if selected_fields
+        .iter()
+        .any(|selected_field| parses_from_str(selected_field, with_args))
+    {
+        let (with_str, with_str_error_items) = generate_with_str(ast, selected_fields, with_args);
+        constructors = quote! {
             #constructors
+            #with_str
+        };
+        with_str_error = with_str_error_items;
+    }
+
+    // Generate the traits of the with-constructors, so that generic code can call them
+    let mut traits = generate_traits(ast, selected_fields, with_args);
+    if let Some(selector_trait) = &with_args.selectors {
+        let selectors = generate_selectors(ast, selected_fields, with_args, selector_trait);
+        traits = quote! {
+            #traits
+            #selectors
+        };
+    }
+
+    // Generate the trait forwarding the with-constructors to this struct flattened in others,
+    // and implement the traits of the flattened fields
+    if with_args.flattenable {
+        let flatten_trait = generate_flatten_trait(ast, &constructors);
+        traits = quote! {
+            #traits
+            #flatten_trait
+        };
+    }
+    let flattened = generate_flattened(ast, fields, with_args);
+    traits = quote! {
+        #traits
+        #flattened
+    };
+
+    // Check the mirror of a remote struct against it
+    if let Some(remote) = &with_args.remote {
+        let remote_assertion = generate_remote_assertion(ast, remote, fields, with_args.exhaustive);
+        traits = quote! {
+            #traits
+            #remote_assertion
+        };
+    }
+
+    let constructors = generate_impl(ast, with_args, constructors);
+    quote! {
+        #constructors
+        #patch
+        #field_enum
+        #with_str_error
+        #traits
+    }
+}
+
+/// Arguments of the `with` attributes on a struct
+struct WithArgs<T> {
+    /// Fields to generate with-constructor for, all fields if empty
+    fields: Vec<T>,
+    /// Typestate transitions, mapping the constructor name to the switched generic parameter
+    transitions: Vec<(Ident, Ident)>,
+    /// Whether generic fields keep their type by default instead of switching it
+    keep_type: bool,
+    /// Types taken as-is by with-constructors instead of using the Into trait
+    exact_types: Vec<Path>,
+    /// Whether flag helpers are generated for every bool field
+    flag: bool,
+    /// Whether conditional with-constructors are generated for every field
+    conditional: bool,
+    /// Whether replace-constructors are generated for every field
+    replace: bool,
+    /// Whether reset-constructors are generated for every field
+    reset: bool,
+    /// Whether a patch struct is generated
+    patch: bool,
+    /// Attributes of the patch struct, like `derive(Debug)`
+    patch_attrs: Vec<Meta>,
+    /// Whether a patch struct is generated with the method computing it between two values
+    diff: bool,
+    /// Whether the field enum and the field value enum are generated
+    field_enum: bool,
+    /// Whether every field can be set from its name and string value
+    from_str: bool,
+    /// Name of the method setting a field from its name and string value, if not `with_str`
+    str_fn: Option<Ident>,
+    /// Whether every field can be set from environment variables
+    env: bool,
+    /// Name of the method setting the fields from environment variables, if not `with_env`
+    env_fn: Option<Ident>,
+    /// Name of the method setting a field from its field value, if not `with_field_value`
+    field_value_fn: Option<Ident>,
+    /// Whether every field can be set from its name and any value
+    with_dyn: bool,
+    /// Whether a trait is generated for the with-constructor of every field
+    traits: bool,
+    /// Trait selecting the field by its marker type to implement for every field, if any
+    selectors: Option<Path>,
+    /// Extension trait declared with the generated methods instead of an inherent impl, if any
+    extension_trait: Option<Ident>,
+    /// Remote struct implementing the generated methods, which the struct mirrors, if any
+    remote: Option<Path>,
+    /// Whether the mirror struct is checked to list all the fields of the remote struct
+    exhaustive: bool,
+    /// Whether the trait forwarding the with-constructors to this struct flattened in others
+    /// is generated
+    flattenable: bool,
+}
+
+/// Argument of the `with` attribute on a struct
+enum WithArg<T> {
+    /// Generate with-constructor for a specific field, like `a` or `1`
+    Field(T),
+    /// Option written without value, like `patch`, which selects the field instead when the
+    /// struct has a field of the same name
+    Keyword(Ident, Box<WithArg<T>>),
+    /// Generate a typestate transition for a generic parameter, like `state = S`
+    Transition(Ident, Ident),
+    /// Keep the type of generic fields by default, like `keep_type`
+    KeepType(bool),
+    /// Take these types as-is instead of using the Into trait, like `exact_types(MyId, Uuid)`
+    ExactTypes(Vec<Path>),
+    /// Generate flag helpers for every bool field, like `flag`
+    Flag(bool),
+    /// Generate conditional with-constructors for every field, like `conditional`
+    Conditional(bool),
+    /// Generate replace-constructors for every field, like `replace`
+    Replace(bool),
+    /// Generate reset-constructors for every field, like `reset`
+    Reset(bool),
+    /// Generate a patch struct, like `patch`
+    Patch(bool),
+    /// Forward attributes to the patch struct, like `patch_attr(derive(Debug, Clone))`
+    PatchAttr(Vec<Meta>),
+    /// Generate a patch struct and the method computing it between two values, like `diff`
+    Diff(bool),
+    /// Generate the field enum and the field value enum, like `field_enum`
+    FieldEnum(bool),
+    /// Set every field from its name and string value, like `from_str`
+    FromStr(bool),
+    /// Rename the method setting a field from its name and string value, like `str_fn = set_str`
+    StrFn(Ident),
+    /// Set every field from environment variables, like `env`
+    Env(bool),
+    /// Rename the method setting the fields from environment variables, like `env_fn = load_env`
+    EnvFn(Ident),
+    /// Rename the method setting a field from its field value, like `field_value_fn = set_field`
+    FieldValueFn(Ident),
+    /// Set every field from its name and any value, like `with_dyn`
+    WithDyn(bool),
+    /// Generate a trait for the with-constructor of every field, like `traits`
+    Traits(bool),
+    /// Implement the trait selecting the field by its marker type, like `selectors` or
+    /// `selectors = crate::With`
+    Selectors(Path),
+    /// Declare the generated methods in an extension trait, like `trait = FooWith`
+    ExtensionTrait(Ident),
+    /// Implement the generated methods for the mirrored remote struct, like
+    /// `remote = "other_crate::Config"`
+    Remote(Path),
+    /// Check that the mirror struct lists all the fields of the remote struct, like `exhaustive`
+    Exhaustive(bool),
+    /// Generate the trait forwarding the with-constructors to this struct flattened in others,
+    /// like `flattenable`
+    Flattenable(bool),
+}
+
+impl<T: Parse> Parse for WithArg<T> {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(Token![trait]) {
+            input.parse::<Token![trait]>()?;
+            input.parse::<Token![=]>()?;
+            return Ok(WithArg::ExtensionTrait(input.parse()?));
+        }
+        if input.peek(syn::Ident) {
+            let key = input.fork().parse::<Ident>()?;
+            let bare = !input.peek2(Token![=]) && !input.peek2(Paren);
+            if let Some(option) = parse_struct_option(&key, input)? {
+                // A bare option may also name a field, which is resolved against the fields later
+                return Ok(if bare {
+                    WithArg::Keyword(key, Box::new(option))
+                } else {
+                    option
+                });
+            }
+            if input.peek2(Token![=]) {
+                input.parse::<Ident>()?;
+                input.parse::<Token![=]>()?;
+                return Ok(WithArg::Transition(key, input.parse()?));
+            }
         }
+        Ok(WithArg::Field(input.parse()?))
     }
 }
 
-fn parse_with_args<T: Parse>(attrs: &[Attribute]) -> Option<Punctuated<T, Comma>> {
-    if let Some(attr) = attrs.iter().find(|attr| attr.path().is_ident("with")) {
-        match &attr.meta {
-            Meta::List(list) => Some(
-                list.parse_args_with(Punctuated::<T, Comma>::parse_terminated)
-                    .expect("Couldn't parse with args"),
-            ),
+/// Parse the struct-level option named by the key, if any
+fn parse_struct_option<T>(key: &Ident, input: ParseStream) -> syn::Result<Option<WithArg<T>>> {
+    if key == "keep_type" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::KeepType(parse_bool_value(input)?)));
+    }
+    if key == "flag" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Flag(parse_bool_value(input)?)));
+    }
+    if key == "conditional" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Conditional(parse_bool_value(input)?)));
+    }
+    if key == "replace" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Replace(parse_bool_value(input)?)));
+    }
+    if key == "reset" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Reset(parse_bool_value(input)?)));
+    }
+    if key == "patch" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Patch(parse_bool_value(input)?)));
+    }
+    if key == "patch_attr" && input.peek2(Paren) {
+        input.parse::<Ident>()?;
+        let content;
+        parenthesized!(content in input);
+        let attrs = Punctuated::<Meta, Comma>::parse_terminated(&content)?;
+        return Ok(Some(WithArg::PatchAttr(attrs.into_iter().collect())));
+    }
+    if key == "diff" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Diff(parse_bool_value(input)?)));
+    }
+    if key == "field_enum" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::FieldEnum(parse_bool_value(input)?)));
+    }
+    if key == "from_str" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::FromStr(parse_bool_value(input)?)));
+    }
+    if key == "env" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Env(parse_bool_value(input)?)));
+    }
+    if key == "str_fn" && input.peek2(Token![=]) {
+        input.parse::<Ident>()?;
+        input.parse::<Token![=]>()?;
+        return Ok(Some(WithArg::StrFn(input.parse()?)));
+    }
+    if key == "field_value_fn" && input.peek2(Token![=]) {
+        input.parse::<Ident>()?;
+        input.parse::<Token![=]>()?;
+        return Ok(Some(WithArg::FieldValueFn(input.parse()?)));
+    }
+    if key == "env_fn" && input.peek2(Token![=]) {
+        input.parse::<Ident>()?;
+        input.parse::<Token![=]>()?;
+        return Ok(Some(WithArg::EnvFn(input.parse()?)));
+    }
+    if key == "with_dyn" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::WithDyn(parse_bool_value(input)?)));
+    }
+    if key == "traits" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Traits(parse_bool_value(input)?)));
+    }
+    if key == "selectors" {
+        input.parse::<Ident>()?;
+        if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            return Ok(Some(WithArg::Selectors(input.parse()?)));
+        }
+        return Ok(Some(WithArg::Selectors(syn::parse_quote! { With })));
+    }
+    if key == "remote" && input.peek2(Token![=]) && input.peek3(LitStr) {
+        input.parse::<Ident>()?;
+        input.parse::<Token![=]>()?;
+        return Ok(Some(WithArg::Remote(input.parse::<LitStr>()?.parse()?)));
+    }
+    if key == "exhaustive" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Exhaustive(parse_bool_value(input)?)));
+    }
+    if key == "flattenable" {
+        input.parse::<Ident>()?;
+        return Ok(Some(WithArg::Flattenable(parse_bool_value(input)?)));
+    }
+    if key == "exact_types" && input.peek2(Paren) {
+        input.parse::<Ident>()?;
+        let content;
+        parenthesized!(content in input);
+        let types = Punctuated::<Path, Comma>::parse_terminated(&content)?;
+        return Ok(Some(WithArg::ExactTypes(types.into_iter().collect())));
+    }
+    Ok(None)
+}
+
+/// Parse the `with` attributes of a struct whose fields have the given names
+fn parse_with_args<T: Parse>(attrs: &[Attribute], field_names: &[&Ident]) -> WithArgs<T> {
+    let mut with_args = WithArgs {
+        fields: Vec::new(),
+        transitions: Vec::new(),
+        keep_type: false,
+        exact_types: Vec::new(),
+        flag: false,
+        conditional: false,
+        replace: false,
+        reset: false,
+        patch: false,
+        patch_attrs: Vec::new(),
+        diff: false,
+        field_enum: false,
+        from_str: false,
+        str_fn: None,
+        env: false,
+        env_fn: None,
+        field_value_fn: None,
+        with_dyn: false,
+        traits: false,
+        selectors: None,
+        extension_trait: None,
+        remote: None,
+        exhaustive: false,
+        flattenable: false,
+    };
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("with")) {
+        let args = match &attr.meta {
+            Meta::List(list) => list
+                .parse_args_with(Punctuated::<WithArg<T>, Comma>::parse_terminated)
+                .expect("Couldn't parse with args"),
             _ => panic!("`with` attribute should like `#[with(a, b, c)]`"),
+        };
+        for arg in args {
+            apply_with_arg(&mut with_args, arg, field_names);
+        }
+    }
+    with_args
+}
+
+fn apply_with_arg<T: Parse>(with_args: &mut WithArgs<T>, arg: WithArg<T>, field_names: &[&Ident]) {
+    match arg {
+        WithArg::Keyword(key, option) => {
+            if field_names.contains(&&key) {
+                let field = syn::parse2(key.to_token_stream()).expect("Couldn't parse field");
+                with_args.fields.push(field);
+            } else {
+                apply_with_arg(with_args, *option, field_names);
+            }
+        }
+        WithArg::Field(field) => with_args.fields.push(field),
+        WithArg::Transition(name, param) => with_args.transitions.push((name, param)),
+        WithArg::KeepType(keep_type) => with_args.keep_type = keep_type,
+        WithArg::ExactTypes(types) => with_args.exact_types.extend(types),
+        WithArg::Flag(flag) => with_args.flag = flag,
+        WithArg::Conditional(conditional) => with_args.conditional = conditional,
+        WithArg::Replace(replace) => with_args.replace = replace,
+        WithArg::Reset(reset) => with_args.reset = reset,
+        WithArg::Patch(patch) => with_args.patch = patch,
+        WithArg::PatchAttr(attrs) => with_args.patch_attrs.extend(attrs),
+        WithArg::Diff(diff) => with_args.diff = diff,
+        WithArg::FieldEnum(field_enum) => with_args.field_enum = field_enum,
+        WithArg::FromStr(from_str) => with_args.from_str = from_str,
+        WithArg::Env(env) => with_args.env = env,
+        WithArg::StrFn(str_fn) => with_args.str_fn = Some(str_fn),
+        WithArg::EnvFn(env_fn) => with_args.env_fn = Some(env_fn),
+        WithArg::FieldValueFn(field_value_fn) => with_args.field_value_fn = Some(field_value_fn),
+        WithArg::WithDyn(with_dyn) => with_args.with_dyn = with_dyn,
+        WithArg::Traits(traits) => with_args.traits = traits,
+        WithArg::Selectors(selector_trait) => with_args.selectors = Some(selector_trait),
+        WithArg::ExtensionTrait(extension_trait) => {
+            with_args.extension_trait = Some(extension_trait)
+        }
+        WithArg::Remote(remote) => with_args.remote = Some(remote),
+        WithArg::Exhaustive(exhaustive) => with_args.exhaustive = exhaustive,
+        WithArg::Flattenable(flattenable) => with_args.flattenable = flattenable,
+    }
+}
+
+fn contains_field<T: PartialEq>(with_args: &WithArgs<T>, item: &T) -> bool {
+    with_args.fields.is_empty() || with_args.fields.contains(item)
+}
+
+/// A field to generate with-constructor for
+struct SelectedField<'a> {
+    field: &'a syn::Field,
+    /// Member accessing the field, like `a` or `0`
+    member: Member,
+    /// Argument name of the with-constructor, like `a` or `field_0`
+    arg_name: Ident,
+    options: FieldOptions,
+    /// Statement recording the change of the field in the tracker, if any
+    track: proc_macro2::TokenStream,
+}
+
+/// Options of a field, from the `with` attribute on it
+#[derive(Default)]
+struct FieldOptions {
+    /// Whether the with-constructor keeps the type of a generic field instead of switching it
+    keep_type: Option<bool>,
+    /// Whether the with-constructor uses the Into trait instead of taking the field type as-is
+    into: Option<bool>,
+    /// Custom function converting the with-constructor argument into the field value
+    with_fn: Option<Path>,
+    /// Argument type of the custom conversion function
+    arg: Option<Type>,
+    /// Whether the with-constructor takes the value wrapped by a smart pointer or container
+    wrap: bool,
+    /// Whether flag helpers are generated for the bool field
+    flag: Option<bool>,
+    /// Whether conditional with-constructors are generated for the field
+    conditional: Option<bool>,
+    /// Whether a replace-constructor is generated for the field
+    replace: Option<bool>,
+    /// Whether a reset-constructor is generated for the field
+    reset: Option<bool>,
+    /// Default value of the field set by the reset-constructor
+    default: Option<Expr>,
+    /// Whether the field records the fields changed by the with-constructors
+    tracker: bool,
+    /// Whether the field can be set from its name and string value
+    from_str: Option<bool>,
+    /// Environment variable setting the field, instead of the prefixed field name
+    env: Option<LitStr>,
+    /// Trait declared elsewhere to implement with the with-constructor of the field
+    impl_trait: Option<Path>,
+    /// Whether the with-constructor takes a closure updating the value of the nested field
+    nested: bool,
+    /// Whether the with-constructors of the field struct are forwarded to it
+    flatten: bool,
+}
+
+/// Argument of the `with` attribute on a field
+enum FieldArg {
+    /// Keep the type of a generic field, like `keep_type` or `keep_type = false`
+    KeepType(bool),
+    /// Use the Into trait or not, like `into`, `into = false` or `exact`
+    Into(bool),
+    /// Convert the argument with a custom function, like `with_fn = Duration::from_secs`
+    WithFn(Path),
+    /// Argument type of the custom conversion function, like `arg = u64`
+    Arg(Type),
+    /// Take the wrapped value of a smart pointer or container, like `wrap`
+    Wrap(bool),
+    /// Generate flag helpers for the bool field, like `flag`
+    Flag(bool),
+    /// Generate conditional with-constructors for the field, like `conditional`
+    Conditional(bool),
+    /// Generate a replace-constructor for the field, like `replace`
+    Replace(bool),
+    /// Generate a reset-constructor for the field, like `reset`
+    Reset(bool),
+    /// Default value of the field, like `default = 8080`
+    Default(Expr),
+    /// Record the fields changed by the with-constructors in this field, like `tracker`
+    Tracker(bool),
+    /// Set the field from its name and string value, like `from_str` or `from_str = false`
+    FromStr(bool),
+    /// Environment variable setting the field, like `env = "PORT"`
+    Env(LitStr),
+    /// Implement a trait declared elsewhere, like `impl_trait = traits::WithName`
+    ImplTrait(Path),
+    /// Update the nested field with a closure, like `nested`
+    Nested(bool),
+    /// Forward the with-constructors of the field struct to it, like `flatten`
+    Flatten(bool),
+}
+
+impl Parse for FieldArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse::<Ident>()?;
+        match key.to_string().as_str() {
+            "keep_type" => Ok(FieldArg::KeepType(parse_bool_value(input)?)),
+            "into" => Ok(FieldArg::Into(parse_bool_value(input)?)),
+            "exact" => Ok(FieldArg::Into(false)),
+            "wrap" => Ok(FieldArg::Wrap(parse_bool_value(input)?)),
+            "flag" => Ok(FieldArg::Flag(parse_bool_value(input)?)),
+            "conditional" => Ok(FieldArg::Conditional(parse_bool_value(input)?)),
+            "replace" => Ok(FieldArg::Replace(parse_bool_value(input)?)),
+            "reset" => Ok(FieldArg::Reset(parse_bool_value(input)?)),
+            "tracker" => Ok(FieldArg::Tracker(parse_bool_value(input)?)),
+            "from_str" => Ok(FieldArg::FromStr(parse_bool_value(input)?)),
+            "nested" => Ok(FieldArg::Nested(parse_bool_value(input)?)),
+            "flatten" => Ok(FieldArg::Flatten(parse_bool_value(input)?)),
+            "default" => {
+                input.parse::<Token![=]>()?;
+                Ok(FieldArg::Default(input.parse()?))
+            }
+            "env" => {
+                input.parse::<Token![=]>()?;
+                Ok(FieldArg::Env(input.parse()?))
+            }
+            "impl_trait" => {
+                input.parse::<Token![=]>()?;
+                Ok(FieldArg::ImplTrait(input.parse()?))
+            }
+            "with_fn" => {
+                input.parse::<Token![=]>()?;
+                Ok(FieldArg::WithFn(input.parse()?))
+            }
+            "arg" => {
+                input.parse::<Token![=]>()?;
+                Ok(FieldArg::Arg(input.parse()?))
+            }
+            _ => Err(syn::Error::new(key.span(), "unknown with field argument")),
+        }
+    }
+}
+
+fn parse_field_options(attrs: &[Attribute]) -> FieldOptions {
+    let mut options = FieldOptions::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("with")) {
+        let args = match &attr.meta {
+            Meta::List(list) => list
+                .parse_args_with(Punctuated::<FieldArg, Comma>::parse_terminated)
+                .expect("Couldn't parse with field args"),
+            _ => panic!("`with` attribute on field should like `#[with(keep_type)]`"),
+        };
+        for arg in args {
+            match arg {
+                FieldArg::KeepType(keep_type) => options.keep_type = Some(keep_type),
+                FieldArg::Into(into) => options.into = Some(into),
+                FieldArg::WithFn(with_fn) => options.with_fn = Some(with_fn),
+                FieldArg::Arg(arg) => options.arg = Some(arg),
+                FieldArg::Wrap(wrap) => options.wrap = wrap,
+                FieldArg::Flag(flag) => options.flag = Some(flag),
+                FieldArg::Conditional(conditional) => options.conditional = Some(conditional),
+                FieldArg::Replace(replace) => options.replace = Some(replace),
+                FieldArg::Reset(reset) => options.reset = Some(reset),
+                FieldArg::Default(default) => options.default = Some(default),
+                FieldArg::Tracker(tracker) => options.tracker = tracker,
+                FieldArg::FromStr(from_str) => options.from_str = Some(from_str),
+                FieldArg::Env(env) => options.env = Some(env),
+                FieldArg::ImplTrait(impl_trait) => options.impl_trait = Some(impl_trait),
+                FieldArg::Nested(nested) => options.nested = nested,
+                FieldArg::Flatten(flatten) => options.flatten = flatten,
+            }
         }
-    } else {
-        None
     }
+    options
 }
 
-fn contains_field<T: Parse + PartialEq>(
-    with_args: &Option<Punctuated<T, Comma>>,
-    item: &T,
-) -> bool {
-    with_args.is_none() || with_args.as_ref().unwrap().iter().any(|arg| arg == item)
+/// Parse the optional value of a flag argument, like `= false`
+fn parse_bool_value(input: ParseStream) -> syn::Result<bool> {
+    if input.peek(Token![=]) {
+        input.parse::<Token![=]>()?;
+        Ok(input.parse::<LitBool>()?.value)
+    } else {
+        Ok(true)
+    }
 }
 
 fn index_generics(generics: &Generics) -> HashMap<Path, TypeParam> {
@@ -370,32 +1176,472 @@ fn index_where_predicates(where_clause: &Option<WhereClause>) -> HashMap<Path, P
         .unwrap_or_default()
 }
 
-fn generate_constructor_for_named(
-    constructor_name: &Ident,
-    field_name: &Ident,
-    field_type: &Type,
-    field_count: usize,
-) -> proc_macro2::TokenStream {
-    let field_arg_type = match field_type {
-        Type::Path(type_path) if is_builtin_numeric_type(&type_path.path) => quote! { #field_type },
-        _ => quote! { impl Into<#field_type> },
-    };
-    if field_count == 1 {
-        quote! {
-            pub fn #constructor_name(self, #field_name: #field_arg_type) -> Self {
-                Self {
-                    #field_name: #field_name.into(),
-                }
-            }
-        }
-    } else {
-        quote! {
-            pub fn #constructor_name(self, #field_name: #field_arg_type) -> Self {
-                Self {
-                    #field_name: #field_name.into(),
-                    ..self
-                }
-            }
+/// Generic parameters switched by a with-constructor
+struct GenericSwitch {
+    /// Generic parameters of the with-constructor, like `WT: Default` or `const WN: usize`
+    params: Vec<GenericParam>,
+    /// Generic arguments of the returned struct, like `WT, Z`
+    ty_generics: Vec<proc_macro2::TokenStream>,
+    /// Argument type of the with-constructor, like `WT` or `[u8; WN]`
+    arg_type: Type,
+    /// Where clause of the with-constructor
+    where_clause: Option<proc_macro2::TokenStream>,
+    /// Switched generic parameters, mapped to the new ones
+    switched: HashMap<Ident, Ident>,
+}
+
+impl GenericSwitch {
+    /// Compute the value of another field in the returned struct
+    fn other_field_value(
+        &self,
+        field: &syn::Field,
+        value: proc_macro2::TokenStream,
+    ) -> proc_macro2::TokenStream {
+        // `PhantomData` markers of the switched generic parameters must be re-typed
+        if is_phantom_data(&field.ty)
+            && self
+                .switched
+                .keys()
+                .any(|ident| type_mentions(&field.ty, ident))
+        {
+            quote! { ::core::marker::PhantomData }
+        } else {
+            value
+        }
+    }
+}
+
+/// Check if the with-constructor of a field can switch some generic parameters of the struct
+fn generic_switch(
+    generics: &Generics,
+    fields: &Punctuated<syn::Field, Token![,]>,
+    field: &syn::Field,
+    generics_map: &HashMap<Path, TypeParam>,
+    where_predicate_map: &HashMap<Path, PredicateType>,
+) -> Option<GenericSwitch> {
+    let field_type = &field.ty;
+    // Check if the type matches some generic parameter
+    if let Type::Path(type_path) = field_type
+        && let Some(generic) = generics_map.get(&type_path.path)
+    {
+        return Some(switch_type_param(generics, generic, where_predicate_map));
+    }
+
+    // Otherwise check if the type uses const generic parameters, like `[u8; N]`,
+    // which are not used by any other field except `PhantomData` markers
+    let mut params = Vec::new();
+    let mut switched = HashMap::new();
+    for const_param in generics.const_params() {
+        let other_fields_mention = fields
+            .iter()
+            .filter(|other_field| {
+                !std::ptr::eq(*other_field, field) && !is_phantom_data(&other_field.ty)
+            })
+            .any(|other_field| type_mentions(&other_field.ty, &const_param.ident));
+        if !type_mentions(field_type, &const_param.ident) || other_fields_mention {
+            continue;
+        }
+        let new_generic = format_ident!("W{}", const_param.ident);
+        let mut const_param = const_param.clone();
+        switched.insert(const_param.ident.clone(), new_generic.clone());
+        const_param.ident = new_generic;
+        const_param.eq_token = None;
+        const_param.default = None;
+        params.push(GenericParam::Const(const_param));
+    }
+    if params.is_empty() {
+        return None;
+    }
+    let arg_type = syn::parse2(replace_idents(field_type.to_token_stream(), &switched))
+        .expect("Couldn't switch const generic parameters");
+    Some(GenericSwitch {
+        params,
+        ty_generics: switch_ty_generics(generics, &switched),
+        arg_type,
+        where_clause: None,
+        switched,
+    })
+}
+
+/// Switch a generic type parameter to a new one, keeping its bounds
+fn switch_type_param(
+    generics: &Generics,
+    generic: &TypeParam,
+    where_predicate_map: &HashMap<Path, PredicateType>,
+) -> GenericSwitch {
+    let mut generic = generic.clone();
+    let new_generic = format_ident!("W{}", generic.ident);
+    let switched = HashMap::from([(generic.ident.clone(), new_generic.clone())]);
+    // Retrieve the where predicate affecting this parameter, if any
+    let where_clause = where_predicate_map
+        .get(&Path::from(generic.ident.clone()))
+        .cloned()
+        .map(|mut predicate| {
+            // And update the bounded type to the new generic ident
+            predicate.bounded_ty = Type::Path(TypePath {
+                qself: None,
+                path: Path::from(new_generic.clone()),
+            });
+            quote! { where #predicate }
+        });
+
+    // Update the generic ident for the new one, so that it doesn't conflict with the existing
+    generic.ident = new_generic.clone();
+    generic.eq_token = None;
+    generic.default = None;
+    GenericSwitch {
+        params: vec![GenericParam::Type(generic)],
+        ty_generics: switch_ty_generics(generics, &switched),
+        arg_type: Type::Path(TypePath {
+            qself: None,
+            path: Path::from(new_generic),
+        }),
+        where_clause,
+        switched,
+    }
+}
+
+/// Determine the new generic arguments of the struct, which are the existing generics
+/// except for the switched ones
+fn switch_ty_generics(
+    generics: &Generics,
+    switched: &HashMap<Ident, Ident>,
+) -> Vec<proc_macro2::TokenStream> {
+    let mut new_generic_params = Vec::new();
+    for param in &generics.params {
+        new_generic_params.push(match param {
+            GenericParam::Type(type_param) => match switched.get(&type_param.ident) {
+                // That must be replaced with the new generic ident
+                Some(new_generic) => new_generic.to_token_stream(),
+                None => type_param.ident.to_token_stream(),
+            },
+            GenericParam::Lifetime(lifetime_param) => lifetime_param.lifetime.to_token_stream(),
+            GenericParam::Const(const_param) => match switched.get(&const_param.ident) {
+                Some(new_generic) => new_generic.to_token_stream(),
+                None => const_param.ident.to_token_stream(),
+            },
+        });
+    }
+    new_generic_params
+}
+
+/// Check if a type is a `PhantomData` marker
+fn is_phantom_data(ty: &Type) -> bool {
+    match ty {
+        Type::Path(type_path) => type_path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "PhantomData"),
+        _ => false,
+    }
+}
+
+/// Check if a type mentions the given ident, like `N` in `[u8; N]`
+fn type_mentions(ty: &Type, ident: &Ident) -> bool {
+    tokens_mention(ty.to_token_stream(), ident, false)
+}
+
+/// Check if a type mentions the given lifetime, like `'a` in `&'a str`
+fn type_mentions_lifetime(ty: &Type, lifetime: &Lifetime) -> bool {
+    tokens_mention(ty.to_token_stream(), &lifetime.ident, true)
+}
+
+fn tokens_mention(tokens: proc_macro2::TokenStream, ident: &Ident, lifetime: bool) -> bool {
+    let mut after_quote = false;
+    tokens.into_iter().any(|token| {
+        let mentions = match &token {
+            TokenTree::Ident(i) => after_quote == lifetime && i == ident,
+            TokenTree::Group(group) => tokens_mention(group.stream(), ident, lifetime),
+            _ => false,
+        };
+        after_quote = matches!(&token, TokenTree::Punct(p) if p.as_char() == '\'');
+        mentions
+    })
+}
+
+/// Check if a type mentions some generic parameters, like `T` in `Vec<T>` or `'a` in `&'a str`
+fn type_mentions_generics(generics: &Generics, ty: &Type) -> bool {
+    generics.params.iter().any(|param| match param {
+        GenericParam::Type(type_param) => type_mentions(ty, &type_param.ident),
+        GenericParam::Lifetime(lifetime_param) => {
+            type_mentions_lifetime(ty, &lifetime_param.lifetime)
+        }
+        GenericParam::Const(const_param) => type_mentions(ty, &const_param.ident),
+    })
+}
+
+/// Marker type of the generic parameters which are not used by the given types, if any
+fn generic_marker(generics: &Generics, types: &[&Type]) -> Option<proc_macro2::TokenStream> {
+    let unused_params = generics
+        .params
+        .iter()
+        .filter_map(|param| match param {
+            GenericParam::Type(type_param)
+                if !types.iter().any(|ty| type_mentions(ty, &type_param.ident)) =>
+            {
+                let ident = &type_param.ident;
+                Some(quote! { fn() -> #ident })
+            }
+            GenericParam::Lifetime(lifetime_param)
+                if !types
+                    .iter()
+                    .any(|ty| type_mentions_lifetime(ty, &lifetime_param.lifetime)) =>
+            {
+                let lifetime = &lifetime_param.lifetime;
+                Some(quote! { &#lifetime () })
+            }
+            GenericParam::Const(const_param)
+                if !types.iter().any(|ty| type_mentions(ty, &const_param.ident)) =>
+            {
+                let ident = &const_param.ident;
+                Some(quote! { [(); #ident] })
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if unused_params.is_empty() {
+        None
+    } else {
+        Some(quote! { ::core::marker::PhantomData<(#(#unused_params,)*)> })
+    }
+}
+
+/// Replace the idents in some tokens, skipping lifetimes
+fn replace_idents(
+    tokens: proc_macro2::TokenStream,
+    replaced: &HashMap<Ident, Ident>,
+) -> proc_macro2::TokenStream {
+    let mut after_quote = false;
+    tokens
+        .into_iter()
+        .map(|token| {
+            let new_token = match token {
+                TokenTree::Ident(ref i) if !after_quote => match replaced.get(i) {
+                    Some(new_ident) => TokenTree::Ident(new_ident.clone()),
+                    None => token,
+                },
+                TokenTree::Group(ref group) => {
+                    let mut new_group =
+                        Group::new(group.delimiter(), replace_idents(group.stream(), replaced));
+                    new_group.set_span(group.span());
+                    TokenTree::Group(new_group)
+                }
+                _ => token,
+            };
+            after_quote = matches!(&new_token, TokenTree::Punct(p) if p.as_char() == '\'');
+            new_token
+        })
+        .collect()
+}
+
+/// Replace the `Self` type in some tokens, like `Option<Self>` into `Option<Foo<T>>`
+fn replace_self(
+    tokens: proc_macro2::TokenStream,
+    replacement: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    tokens
+        .into_iter()
+        .flat_map(|token| match token {
+            TokenTree::Ident(ref i) if i == "Self" => replacement.clone(),
+            TokenTree::Group(ref group) => {
+                let mut new_group =
+                    Group::new(group.delimiter(), replace_self(group.stream(), replacement));
+                new_group.set_span(group.span());
+                TokenTree::Group(new_group).into()
+            }
+            _ => token.into(),
+        })
+        .collect()
+}
+
+/// Conversion of the with-constructor argument into the field value
+struct Conversion<'a> {
+    kind: ConversionKind<'a>,
+    /// Type produced by the conversion, which is the field type unless wrapped
+    target: Type,
+    /// Constructors wrapping the converted value into the field value, outermost first
+    wrappers: Vec<proc_macro2::TokenStream>,
+}
+
+/// Kind of conversion of the with-constructor argument
+enum ConversionKind<'a> {
+    /// Take the target type itself, like `a: i32`
+    Exact,
+    /// Take anything convertible with the Into trait, like `a: impl Into<String>`
+    Into,
+    /// Take the given argument type and convert it with a custom function
+    WithFn(&'a Path, &'a Type),
+    /// Take an implementation of the target trait object, like `f: impl Fn() + 'static`
+    ImplTrait,
+}
+
+impl<'a> Conversion<'a> {
+    /// Determine the conversion of a field, from its options and the struct-level exact types
+    fn of(field_type: &'a Type, field_options: &'a FieldOptions, exact_types: &[Path]) -> Self {
+        if let Some(with_fn) = &field_options.with_fn {
+            let arg_type = field_options.arg.as_ref().unwrap_or(field_type);
+            return Conversion {
+                kind: ConversionKind::WithFn(with_fn, arg_type),
+                target: field_type.clone(),
+                wrappers: Vec::new(),
+            };
+        }
+
+        // Unwrap the smart pointers and containers when wrapping, like `Option<Box<T>>`
+        let mut target = field_type.clone();
+        let mut wrappers = Vec::new();
+        if field_options.wrap {
+            while let Some((wrapper, inner)) = unwrap_type(&target) {
+                wrappers.push(wrapper);
+                target = inner;
+            }
+        } else if field_options.into.is_none() {
+            // Trait objects behind a smart pointer are unwrapped unless the pointer is taken with
+            // `into`, like `Box<dyn Fn()>` or `Arc<dyn Store>`
+            if let Some((wrapper, inner @ Type::TraitObject(_))) = unwrap_type(&target) {
+                wrappers.push(wrapper);
+                target = inner;
+            }
+        }
+
+        let kind = match field_options.into {
+            Some(true) => ConversionKind::Into,
+            Some(false) => ConversionKind::Exact,
+            None => match &target {
+                // Take the trait implementation directly, which is coerced into the trait object
+                Type::TraitObject(_) => ConversionKind::ImplTrait,
+                Type::Path(type_path)
+                    if is_builtin_numeric_type(&type_path.path)
+                        || exact_types.contains(&type_path.path) =>
+                {
+                    ConversionKind::Exact
+                }
+                _ => ConversionKind::Into,
+            },
+        };
+        Conversion {
+            kind,
+            target,
+            wrappers,
+        }
+    }
+
+    /// Whether the with-constructor takes the field value itself
+    fn takes_field_type(&self) -> bool {
+        self.wrappers.is_empty()
+            && matches!(self.kind, ConversionKind::Exact | ConversionKind::Into)
+    }
+
+    /// Argument type of the with-constructor
+    fn arg_type(&self) -> proc_macro2::TokenStream {
+        let target = &self.target;
+        match self.kind {
+            ConversionKind::Exact => quote! { #target },
+            ConversionKind::Into => quote! { impl Into<#target> },
+            ConversionKind::WithFn(_, arg_type) => quote! { #arg_type },
+            ConversionKind::ImplTrait => match target {
+                Type::TraitObject(trait_object) => {
+                    let bounds = &trait_object.bounds;
+                    // Trait objects without lifetime bound default to the static lifetime
+                    if bounds
+                        .iter()
+                        .any(|bound| matches!(bound, TypeParamBound::Lifetime(_)))
+                    {
+                        quote! { impl #bounds }
+                    } else {
+                        quote! { impl #bounds + 'static }
+                    }
+                }
+                _ => quote! { impl Into<#target> },
+            },
+        }
+    }
+
+    /// Convert the with-constructor argument into the field value
+    fn convert(&self, arg_name: &Ident) -> proc_macro2::TokenStream {
+        let value = match self.kind {
+            ConversionKind::Exact | ConversionKind::ImplTrait => quote! { #arg_name },
+            ConversionKind::Into => quote! { #arg_name.into() },
+            ConversionKind::WithFn(with_fn, _) => quote! { #with_fn(#arg_name) },
+        };
+        self.wrappers
+            .iter()
+            .rev()
+            .fold(value, |value, wrapper| quote! { #wrapper(#value) })
+    }
+}
+
+/// Split a smart pointer or container type into its constructor and the wrapped type,
+/// like `Box<T>` into `Box::new` and `T`
+fn unwrap_type(ty: &Type) -> Option<(proc_macro2::TokenStream, Type)> {
+    let Type::Path(type_path) = ty else {
+        return None;
+    };
+    let segment = type_path.path.segments.last()?;
+    let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+    let inner = arguments.args.iter().find_map(|arg| match arg {
+        GenericArgument::Type(inner) => Some(inner),
+        _ => None,
+    })?;
+    let wrapper = match segment.ident.to_string().as_str() {
+        "Box" => quote! { ::std::boxed::Box::new },
+        "Rc" => quote! { ::std::rc::Rc::new },
+        "Arc" => quote! { ::std::sync::Arc::new },
+        "Cell" => quote! { ::core::cell::Cell::new },
+        "RefCell" => quote! { ::core::cell::RefCell::new },
+        "Mutex" => quote! { ::std::sync::Mutex::new },
+        "RwLock" => quote! { ::std::sync::RwLock::new },
+        "Option" => quote! { ::core::option::Option::Some },
+        // The owned value of a `Cow` can't be unwrapped any further
+        "Cow" => {
+            let owned = syn::parse_quote! { <#inner as ::std::borrow::ToOwned>::Owned };
+            return Some((quote! { ::std::borrow::Cow::Owned }, owned));
+        }
+        _ => return None,
+    };
+    Some((wrapper, inner.clone()))
+}
+
+fn generate_constructor_for_named(
+    constructor_name: &Ident,
+    field_name: &Ident,
+    conversion: &Conversion,
+    field_count: usize,
+    track: &proc_macro2::TokenStream,
+    remote: bool,
+) -> proc_macro2::TokenStream {
+    let field_arg_type = conversion.arg_type();
+    let field_value = conversion.convert(field_name);
+    let receiver = self_receiver(track);
+    if remote {
+        // Remote structs may have private fields, which don't allow the struct update syntax
+        quote! {
+            pub fn #constructor_name(mut self, #field_name: #field_arg_type) -> Self {
+                self.#field_name = #field_value;
+                #track
+                self
+            }
+        }
+    } else if field_count == 1 {
+        quote! {
+            pub fn #constructor_name(#receiver, #field_name: #field_arg_type) -> Self {
+                Self {
+                    #field_name: #field_value,
+                }
+            }
+        }
+    } else {
+        quote! {
+            pub fn #constructor_name(#receiver, #field_name: #field_arg_type) -> Self {
+                #track
+                Self {
+                    #field_name: #field_value,
+                    ..self
+                }
+            }
         }
     }
 }
@@ -404,22 +1650,1249 @@ fn generate_constructor_for_unnamed(
     constructor_name: &Ident,
     field_index: Index,
     field_name: &Ident,
-    field_type: &Type,
+    conversion: &Conversion,
+    track: &proc_macro2::TokenStream,
 ) -> proc_macro2::TokenStream {
-    let field_arg_type = match field_type {
-        Type::Path(type_path) if is_builtin_numeric_type(&type_path.path) => {
-            quote! { #field_type }
+    let field_arg_type = conversion.arg_type();
+    let field_value = conversion.convert(field_name);
+    quote! {
+        pub fn #constructor_name(mut self, #field_name: #field_arg_type) -> Self {
+            self.#field_index = #field_value;
+            #track
+            self
+        }
+    }
+}
+
+/// Generate the with-constructor of a nested field, which takes a closure updating its value,
+/// like `with_tls(|tls| tls.with_cert(cert))`
+fn generate_nested_constructor(
+    constructor_name: &Ident,
+    selected_field: &SelectedField,
+    field_name: &Ident,
+) -> proc_macro2::TokenStream {
+    let SelectedField {
+        field,
+        member,
+        track,
+        ..
+    } = selected_field;
+    let field_type = &field.ty;
+    quote! {
+        pub fn #constructor_name(
+            mut self,
+            #field_name: impl FnOnce(#field_type) -> #field_type,
+        ) -> Self {
+            self.#member = #field_name(self.#member);
+            #track
+            self
+        }
+    }
+}
+
+/// Receiver of a with-constructor, which must be mutable to record the change
+fn self_receiver(track: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    if track.is_empty() {
+        quote! { self }
+    } else {
+        quote! { mut self }
+    }
+}
+
+/// Generate the optional helpers of a field, next to its with-constructor
+fn generate_field_helpers<T>(
+    selected_field: &SelectedField,
+    with_args: &WithArgs<T>,
+    conversion: &Conversion,
+) -> proc_macro2::TokenStream {
+    let SelectedField {
+        field,
+        member,
+        arg_name,
+        options,
+        track,
+    } = selected_field;
+    let field_type = &field.ty;
+    let mut helpers = quote!();
+
+    // Generate flag helpers for bool fields
+    if options
+        .flag
+        .unwrap_or(with_args.flag && is_bool(field_type))
+    {
+        if !is_bool(field_type) {
+            panic!("`flag` is only supported on bool fields");
+        }
+        let flag_constructors = generate_flag_constructors(member, track);
+        helpers = quote! {
+            #helpers
+            #flag_constructors
+        };
+    }
+
+    // Generate conditional with-constructors
+    if options.conditional.unwrap_or(with_args.conditional) {
+        let conditional_constructors =
+            generate_conditional_constructors(member, arg_name, conversion, track);
+        helpers = quote! {
+            #helpers
+            #conditional_constructors
+        };
+    }
+
+    // Generate replace-constructor returning the previous value
+    if options.replace.unwrap_or(with_args.replace) {
+        let replace_constructor =
+            generate_replace_constructor(member, arg_name, field_type, conversion, track);
+        helpers = quote! {
+            #helpers
+            #replace_constructor
+        };
+    }
+
+    // Generate reset-constructor setting the default value
+    let reset = options.default.is_some() || with_args.reset;
+    if options.reset.unwrap_or(reset) {
+        let reset_constructor =
+            generate_reset_constructor(member, field_type, &options.default, track);
+        helpers = quote! {
+            #helpers
+            #reset_constructor
+        };
+    }
+    helpers
+}
+
+/// Generate the patch struct `FooPatch`, with an optional value for every field,
+/// and `Foo::apply` setting the fields of a patch through the with-constructors
+fn generate_patch<T>(
+    ast: &syn::DeriveInput,
+    selected_fields: &[SelectedField],
+    with_args: &WithArgs<T>,
+    named: bool,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let name = &ast.ident;
+    let vis = &ast.vis;
+    let generics = &ast.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let patch_name = format_ident!("{}Patch", name);
+    let patch = hygienic_ident("patch");
+
+    let mut patch_fields = Vec::new();
+    let mut default_values = Vec::new();
+    let mut constructors = quote!();
+    let mut applied_fields = quote!();
+    let mut diff_fields = quote!();
+    for (position, selected_field) in selected_fields.iter().enumerate() {
+        let SelectedField {
+            field,
+            member,
+            arg_name,
+            options,
+            ..
+        } = selected_field;
+        let field_vis = &field.vis;
+        let field_type = &field.ty;
+        let patch_member = if named {
+            member.clone()
+        } else {
+            Member::from(position)
+        };
+        if named {
+            patch_fields.push(quote! { #field_vis #patch_member: Option<#field_type> });
+        } else {
+            patch_fields.push(quote! { #field_vis Option<#field_type> });
+        }
+        default_values.push(quote! { #patch_member: None });
+
+        let constructor_name = field_method_name("with_", member, "");
+        let conversion = Conversion::of(field_type, options, &with_args.exact_types);
+        let arg_type = conversion.arg_type();
+        let field_value = conversion.convert(arg_name);
+        constructors = quote! {
+            #constructors
+            pub fn #constructor_name(mut self, #arg_name: #arg_type) -> Self {
+                self.#patch_member = Some(#field_value);
+                self
+            }
+        };
+
+        let apply_value = generate_set_value(selected_field, &conversion, with_args);
+        applied_fields = quote! {
+            #applied_fields
+            if let Some(#arg_name) = #patch.#patch_member {
+                #apply_value
+            }
+        };
+        diff_fields = quote! {
+            #diff_fields
+            if self.#member != other.#member {
+                #patch.#patch_member = Some(::core::clone::Clone::clone(&other.#member));
+            }
+        };
+    }
+
+    // Use the generic parameters which are not used by the fields
+    let field_types = selected_fields
+        .iter()
+        .map(|selected_field| &selected_field.field.ty)
+        .collect::<Vec<_>>();
+    if let Some(marker) = generic_marker(generics, &field_types) {
+        let marker_member = Member::from(selected_fields.len());
+        if named {
+            patch_fields.push(quote! { #[doc(hidden)] #vis __marker: #marker });
+            default_values.push(quote! { __marker: ::core::marker::PhantomData });
+        } else {
+            patch_fields.push(quote! { #[doc(hidden)] #vis #marker });
+            default_values.push(quote! { #marker_member: ::core::marker::PhantomData });
+        }
+    }
+
+    let patch_attrs = &with_args.patch_attrs;
+    let patch_struct = if named {
+        quote! {
+            #(#[#patch_attrs])*
+            #vis struct #patch_name #generics #where_clause {
+                #(#patch_fields),*
+            }
+        }
+    } else {
+        quote! {
+            #(#[#patch_attrs])*
+            #vis struct #patch_name #generics ( #(#patch_fields),* ) #where_clause;
         }
-        _ => quote! { impl Into<#field_type> },
+    };
+    let mut apply = quote! {
+        pub fn apply(mut self, #patch: #patch_name #ty_generics) -> Self {
+            #applied_fields
+            self
+        }
+    };
+    // The fields must be comparable and cloneable to compute the patch between two values
+    if with_args.diff {
+        apply = quote! {
+            #apply
+            pub fn diff(&self, other: &Self) -> #patch_name #ty_generics
+            where
+                #(#field_types: ::core::cmp::PartialEq + ::core::clone::Clone,)*
+            {
+                let mut #patch = <#patch_name #ty_generics as ::core::default::Default>::default();
+                #diff_fields
+                #patch
+            }
+        };
+    }
+    let patch_items = quote! {
+        #patch_struct
+
+        #[automatically_derived]
+        impl #impl_generics ::core::default::Default for #patch_name #ty_generics #where_clause {
+            fn default() -> Self {
+                Self {
+                    #(#default_values),*
+                }
+            }
+        }
+
+        #[automatically_derived]
+        impl #impl_generics #patch_name #ty_generics #where_clause {
+            #constructors
+        }
+    };
+    (apply, patch_items)
+}
+
+/// Find the field recording the fields changed by the with-constructors, if any
+fn find_tracker(fields: &Punctuated<syn::Field, Token![,]>) -> Option<Member> {
+    let mut trackers = fields
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| parse_field_options(&field.attrs).tracker)
+        .map(|(index, field)| match &field.ident {
+            Some(field_name) => Member::from(field_name.clone()),
+            None => Member::from(index),
+        });
+    let tracker = trackers.next();
+    if trackers.next().is_some() {
+        panic!("only one `tracker` field is supported");
+    }
+    tracker
+}
+
+/// Variant of the field enum for a field, like `FooBar` for `foo_bar` or `Field0` for `0`
+fn field_variant(member: &Member) -> Ident {
+    match member {
+        Member::Named(field_name) => {
+            let field_name = field_name.to_string();
+            let variant = field_name
+                .trim_start_matches("r#")
+                .split('_')
+                .filter(|word| !word.is_empty())
+                .map(|word| {
+                    let mut chars = word.chars();
+                    let first = chars.next().unwrap().to_ascii_uppercase();
+                    std::iter::once(first).chain(chars).collect::<String>()
+                })
+                .collect::<String>();
+            format_ident!("{}", variant)
+        }
+        Member::Unnamed(index) => format_ident!("Field{}", index),
+    }
+}
+
+/// Type implementing the generated methods, which is the remote struct if any
+fn self_type<T>(ast: &syn::DeriveInput, with_args: &WithArgs<T>) -> proc_macro2::TokenStream {
+    let (_, ty_generics, _) = ast.generics.split_for_impl();
+    match &with_args.remote {
+        Some(remote) => quote! { #remote #ty_generics },
+        None => {
+            let name = &ast.ident;
+            quote! { #name #ty_generics }
+        }
+    }
+}
+
+/// Generate the assertion that the fields of the mirror struct match the remote struct,
+/// with the same types, and that they are all the fields of the remote struct if exhaustive
+fn generate_remote_assertion(
+    ast: &syn::DeriveInput,
+    remote: &Path,
+    fields: &Punctuated<syn::Field, Token![,]>,
+    exhaustive: bool,
+) -> proc_macro2::TokenStream {
+    let generics = &ast.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let value = hygienic_ident("value");
+    let field_assertions = fields.iter().enumerate().map(|(index, field)| {
+        let member = match &field.ident {
+            Some(field_name) => Member::from(field_name.clone()),
+            None => Member::from(index),
+        };
+        let field_type = &field.ty;
+        quote! {
+            let _: ::core::marker::PhantomData<#field_type> = type_of(&#value.#member);
+        }
+    });
+    // Destructuring without rest pattern fails when the remote struct has other fields
+    let exhaustive_assertion = match (exhaustive, &ast.data) {
+        (true, syn::Data::Struct(data)) if matches!(data.fields, syn::Fields::Unnamed(_)) => {
+            let wildcards = fields.iter().map(|_| quote! { _ });
+            quote! { let #remote( #(#wildcards),* ) = #value; }
+        }
+        (true, _) => {
+            let field_names = fields.iter().map(|field| &field.ident);
+            quote! { let #remote { #(#field_names: _),* } = #value; }
+        }
+        (false, _) => quote!(),
     };
     quote! {
-        pub fn #constructor_name(mut self, #field_name: #field_arg_type) -> Self {
-            self.#field_index = #field_name.into();
+        const _: () = {
+            fn type_of<T: ?Sized>(_: &T) -> ::core::marker::PhantomData<T> {
+                ::core::marker::PhantomData
+            }
+            #[allow(dead_code)]
+            fn assert_remote_fields #impl_generics (#value: &#remote #ty_generics) #where_clause {
+                #(#field_assertions)*
+                #exhaustive_assertion
+            }
+        };
+    }
+}
+
+/// Name of the extension trait declaring the generated methods, if any
+fn extension_trait_name<T>(with_args: &WithArgs<T>) -> Option<Ident> {
+    // Remote structs always need an extension trait, named after them by default
+    with_args.extension_trait.clone().or_else(|| {
+        with_args.remote.as_ref().map(|remote| {
+            let remote_name = &remote.segments.last().expect("Empty remote path").ident;
+            format_ident!("{}With", remote_name)
+        })
+    })
+}
+
+/// Generate the impl with the generated methods, which is either inherent or the impl of the
+/// extension trait declared next to it
+fn generate_impl<T>(
+    ast: &syn::DeriveInput,
+    with_args: &WithArgs<T>,
+    constructors: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let vis = &ast.vis;
+    let generics = &ast.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let self_type = self_type(ast, with_args);
+    let Some(extension_trait) = extension_trait_name(with_args) else {
+        return quote! {
+            #[automatically_derived]
+            impl #impl_generics #self_type #where_clause {
+                #constructors
+            }
+        };
+    };
+
+    // Derive the trait items from the generated methods, which can't be public in a trait impl
+    let item_impl: syn::ItemImpl = syn::parse2(quote! {
+        impl #impl_generics #self_type #where_clause {
+            #constructors
+        }
+    })
+    .expect("Couldn't parse generated methods");
+    let mut trait_items = Vec::new();
+    let mut impl_items = Vec::new();
+    for item in item_impl.items {
+        match item {
+            syn::ImplItem::Fn(mut item_fn) => {
+                item_fn.vis = syn::Visibility::Inherited;
+                let sig = declaration_sig(&item_fn.sig);
+                trait_items.push(quote! { #sig; });
+                impl_items.push(item_fn.into_token_stream());
+            }
+            syn::ImplItem::Const(mut item_const) => {
+                item_const.vis = syn::Visibility::Inherited;
+                let ident = &item_const.ident;
+                let ty = &item_const.ty;
+                trait_items.push(quote! { const #ident: #ty; });
+                impl_items.push(item_const.into_token_stream());
+            }
+            item => impl_items.push(item.into_token_stream()),
+        }
+    }
+    quote! {
+        #vis trait #extension_trait #generics: Sized #where_clause {
+            #(#trait_items)*
+        }
+
+        #[automatically_derived]
+        impl #impl_generics #extension_trait #ty_generics for #self_type #where_clause {
+            #(#impl_items)*
+        }
+    }
+}
+
+/// Signature of a trait method declaration, as patterns aren't allowed in declarations without
+/// body, like `mut self`
+fn declaration_sig(sig: &syn::Signature) -> syn::Signature {
+    let mut sig = sig.clone();
+    for input in sig.inputs.iter_mut() {
+        match input {
+            syn::FnArg::Receiver(receiver) if receiver.reference.is_none() => {
+                receiver.mutability = None;
+                receiver.ty = syn::parse_quote! { Self };
+            }
+            syn::FnArg::Receiver(_) => {}
+            syn::FnArg::Typed(pat_type) => {
+                if let syn::Pat::Ident(pat_ident) = pat_type.pat.as_mut() {
+                    pat_ident.mutability = None;
+                }
+            }
+        }
+    }
+    sig
+}
+
+/// Generate the trait `FooWithFlatten`, implemented by the structs flattening `Foo` in a field,
+/// with the with-constructors of `Foo` forwarded to that field
+fn generate_flatten_trait(
+    ast: &syn::DeriveInput,
+    constructors: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let name = &ast.ident;
+    let vis = &ast.vis;
+    let generics = &ast.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let trait_name = format_ident!("{}WithFlatten", name);
+    let map_name = format_ident!("map_{}", to_snake_case(&name.to_string()));
+    let inner = hygienic_ident("inner");
+
+    let item_impl: syn::ItemImpl = syn::parse2(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #constructors
+        }
+    })
+    .expect("Couldn't parse generated methods");
+    let mut methods = quote!();
+    for item in item_impl.items {
+        let syn::ImplItem::Fn(item_fn) = item else {
+            continue;
+        };
+        // Only forward the methods consuming and returning the struct itself
+        let sig = &item_fn.sig;
+        let by_value = sig
+            .receiver()
+            .is_some_and(|receiver| receiver.reference.is_none());
+        let returns_self = matches!(
+            &sig.output,
+            syn::ReturnType::Type(_, ty) if matches!(ty.as_ref(), Type::Path(type_path) if type_path.path.is_ident("Self"))
+        );
+        if !by_value || !returns_self || !sig.generics.params.is_empty() {
+            continue;
+        }
+        let method_name = &sig.ident;
+        let args = sig.inputs.iter().filter_map(|input| match input {
+            syn::FnArg::Typed(pat_type) => match pat_type.pat.as_ref() {
+                syn::Pat::Ident(pat_ident) => Some(&pat_ident.ident),
+                _ => None,
+            },
+            syn::FnArg::Receiver(_) => None,
+        });
+        // `Self` is the flattening struct in the trait, so the arguments name this struct instead
+        let mut sig = declaration_sig(sig);
+        for input in sig.inputs.iter_mut() {
+            if let syn::FnArg::Typed(pat_type) = input {
+                let ty = replace_self(
+                    pat_type.ty.to_token_stream(),
+                    &quote! { #name #ty_generics },
+                );
+                *pat_type.ty = syn::parse2(ty).expect("Couldn't parse argument type");
+            }
+        }
+        methods = quote! {
+            #methods
+            #sig {
+                self.#map_name(move |#inner| #inner.#method_name(#(#args),*))
+            }
+        };
+    }
+    quote! {
+        #vis trait #trait_name #generics: Sized #where_clause {
+            fn #map_name(self, f: impl FnOnce(#name #ty_generics) -> #name #ty_generics) -> Self;
+            #methods
+        }
+    }
+}
+
+/// Implement the traits forwarding the with-constructors of the flattened fields to them,
+/// whether the fields have with-constructors or not
+fn generate_flattened<T>(
+    ast: &syn::DeriveInput,
+    fields: &Punctuated<syn::Field, Token![,]>,
+    with_args: &WithArgs<T>,
+) -> proc_macro2::TokenStream {
+    let (impl_generics, _, where_clause) = ast.generics.split_for_impl();
+    let self_type = self_type(ast, with_args);
+
+    let mut flattened = quote!();
+    for (index, field) in fields.iter().enumerate() {
+        if !parse_field_options(&field.attrs).flatten {
+            continue;
+        }
+        let field_type = &field.ty;
+        let member = match &field.ident {
+            Some(field_name) => Member::from(field_name.clone()),
+            None => Member::from(index),
+        };
+        // The trait is declared next to the flattened struct, like `opts::CommonOptsWithFlatten`
+        let mut trait_path = match field_type {
+            Type::Path(type_path) => type_path.path.clone(),
+            _ => panic!("`flatten` is only supported on struct fields"),
+        };
+        let segment = trait_path.segments.last_mut().expect("Empty type path");
+        let map_name = format_ident!("map_{}", to_snake_case(&segment.ident.to_string()));
+        segment.ident = format_ident!("{}WithFlatten", segment.ident);
+        flattened = quote! {
+            #flattened
+            #[automatically_derived]
+            impl #impl_generics #trait_path for #self_type #where_clause {
+                fn #map_name(mut self, f: impl FnOnce(#field_type) -> #field_type) -> Self {
+                    self.#member = f(self.#member);
+                    self
+                }
+            }
+        };
+    }
+    flattened
+}
+
+/// Name of a field, like `foo_bar` or `0`
+fn field_name_str(member: &Member) -> String {
+    match member {
+        Member::Named(field_name) => field_name.to_string().trim_start_matches("r#").to_string(),
+        Member::Unnamed(index) => index.index.to_string(),
+    }
+}
+
+/// Name of a method generated for the struct, either renamed or the default one, which must not
+/// conflict with the with-constructors, like `with_env` with the one of a field `env`
+fn generated_method_name(
+    renamed: &Option<Ident>,
+    default: &str,
+    rename_hint: &str,
+    selected_fields: &[SelectedField],
+) -> Ident {
+    let method_name = match renamed {
+        Some(renamed) => renamed.clone(),
+        None => Ident::new(default, Span::call_site()),
+    };
+    if let Some(selected_field) = selected_fields.iter().find(|selected_field| {
+        field_method_name("with_", &selected_field.member, "") == method_name
+    }) {
+        panic!(
+            "`{method_name}` conflicts with the with-constructor of field `{}`, rename it like `#[with({rename_hint})]`",
+            field_name_str(&selected_field.member)
+        );
+    }
+    method_name
+}
+
+/// Name of a generated method of the field, like `reset_type` for `r#type` and `with_0_if` for
+/// the first field of a tuple struct
+fn field_method_name(prefix: &str, member: &Member, suffix: &str) -> Ident {
+    format_ident!("{}{}{}", prefix, field_name_str(member), suffix)
+}
+
+/// Generate the statement recording the change of a field in the tracker, if any
+fn generate_track(
+    tracker: &Option<Member>,
+    field_enum_name: &Ident,
+    member: &Member,
+) -> proc_macro2::TokenStream {
+    let Some(tracker) = tracker else {
+        return quote!();
+    };
+    let variant = field_variant(member);
+    quote! {
+        if !self.#tracker.contains(&#field_enum_name::#variant) {
+            self.#tracker.push(#field_enum_name::#variant);
+        }
+    }
+}
+
+/// Generate the field enum `FooField`, with a variant for every field
+fn generate_field_enum(
+    ast: &syn::DeriveInput,
+    field_enum_name: &Ident,
+    selected_fields: &[SelectedField],
+) -> proc_macro2::TokenStream {
+    let vis = &ast.vis;
+    let variants = selected_fields
+        .iter()
+        .map(|selected_field| field_variant(&selected_field.member))
+        .collect::<Vec<_>>();
+    let field_names = selected_fields
+        .iter()
+        .map(|selected_field| field_name_str(&selected_field.member));
+    let name_arms = variants
+        .iter()
+        .zip(field_names)
+        .map(|(variant, field_name)| {
+            quote! { Self::#variant => #field_name }
+        });
+    quote! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #vis enum #field_enum_name {
+            #(#variants),*
+        }
+
+        #[automatically_derived]
+        impl #field_enum_name {
+            pub const ALL: &[Self] = &[#(Self::#variants),*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    #(#name_arms,)*
+                }
+            }
+        }
+    }
+}
+
+/// Generate the methods reading the fields changed by the with-constructors
+fn generate_tracker_methods(field_enum_name: &Ident, tracker: &Member) -> proc_macro2::TokenStream {
+    quote! {
+        pub fn changed_fields(&self) -> &[#field_enum_name] {
+            &self.#tracker
+        }
+        pub fn is_changed(&self, field: #field_enum_name) -> bool {
+            self.#tracker.contains(&field)
+        }
+        pub fn clear_changes(&mut self) {
+            self.#tracker.clear();
+        }
+    }
+}
+
+/// Generate the field value enum `FooFieldValue`, with a variant holding the value of every
+/// field, and `Foo::with_field_value` setting the field of a value
+fn generate_field_value<T>(
+    ast: &syn::DeriveInput,
+    field_enum_name: &Ident,
+    selected_fields: &[SelectedField],
+    with_args: &WithArgs<T>,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let vis = &ast.vis;
+    let generics = &ast.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let field_value_name = format_ident!("{}Value", field_enum_name);
+    let value = hygienic_ident("value");
+
+    let mut variants = Vec::new();
+    let mut field_arms = Vec::new();
+    let mut assigned_fields = Vec::new();
+    for selected_field in selected_fields {
+        let SelectedField {
+            field,
+            member,
+            track,
+            ..
+        } = selected_field;
+        let field_type = &field.ty;
+        let variant = field_variant(member);
+        variants.push(quote! { #variant(#field_type) });
+        field_arms.push(quote! { Self::#variant(_) => #field_enum_name::#variant });
+        assigned_fields.push(quote! {
+            #field_value_name::#variant(#value) => {
+                self.#member = #value;
+                #track
+            }
+        });
+    }
+
+    // Use the generic parameters which are not used by the fields
+    let field_types = selected_fields
+        .iter()
+        .map(|selected_field| &selected_field.field.ty)
+        .collect::<Vec<_>>();
+    if let Some(marker) = generic_marker(generics, &field_types) {
+        // The marker variant can't be constructed, as the Infallible value doesn't exist
+        let never = hygienic_ident("never");
+        variants.push(quote! {
+            #[doc(hidden)]
+            __Marker(#marker, ::core::convert::Infallible)
+        });
+        field_arms.push(quote! { Self::__Marker(_, #never) => match *#never {} });
+        assigned_fields.push(quote! { #field_value_name::__Marker(_, #never) => match #never {} });
+    }
+
+    let field_value_fn = generated_method_name(
+        &with_args.field_value_fn,
+        "with_field_value",
+        "field_value_fn = set_field",
+        selected_fields,
+    );
+    let with_field_value = quote! {
+        pub fn #field_value_fn(mut self, #value: #field_value_name #ty_generics) -> Self {
+            match #value {
+                #(#assigned_fields)*
+            }
             self
         }
+    };
+    let field_value_items = quote! {
+        #vis enum #field_value_name #generics #where_clause {
+            #(#variants),*
+        }
+
+        #[automatically_derived]
+        impl #impl_generics #field_value_name #ty_generics #where_clause {
+            pub fn field(&self) -> #field_enum_name {
+                match self {
+                    #(#field_arms,)*
+                }
+            }
+        }
+    };
+    (with_field_value, field_value_items)
+}
+
+/// Generate the statements setting a field to the value of its argument name, going through
+/// the with-constructor unless it doesn't take the field value itself
+fn generate_set_value<T>(
+    selected_field: &SelectedField,
+    conversion: &Conversion,
+    with_args: &WithArgs<T>,
+) -> proc_macro2::TokenStream {
+    let SelectedField {
+        member,
+        arg_name,
+        options,
+        track,
+        ..
+    } = selected_field;
+    // The with-constructor of a nested field takes a closure instead
+    if conversion.takes_field_type() && !options.nested {
+        let constructor_name = field_method_name("with_", member, "");
+        // The with-constructor is called by its path, as the traits of the fields also declare it
+        match extension_trait_name(with_args) {
+            Some(extension_trait) => quote! {
+                self = <Self as #extension_trait>::#constructor_name(self, #arg_name);
+            },
+            None => quote! { self = Self::#constructor_name(self, #arg_name); },
+        }
+    } else {
+        quote! { self.#member = #arg_name; #track }
     }
 }
 
+/// Generate `Foo::WITH_FIELDS` listing the field names, and `Foo::with_dyn` setting a field
+/// from its name and any value, which gives the value back if it can't be set
+fn generate_reflection<T>(
+    selected_fields: &[SelectedField],
+    with_args: &WithArgs<T>,
+) -> proc_macro2::TokenStream {
+    let field_names = selected_fields
+        .iter()
+        .map(|selected_field| field_name_str(&selected_field.member))
+        .collect::<Vec<_>>();
+    let with_fields = quote! {
+        pub const WITH_FIELDS: &'static [&'static str] = &[#(#field_names),*];
+    };
+    if !with_args.with_dyn {
+        return with_fields;
+    }
+
+    let name = hygienic_ident("name");
+    let value = hygienic_ident("value");
+    let mut field_types = Vec::new();
+    let mut downcast_fields = quote!();
+    for (selected_field, field_name) in selected_fields.iter().zip(&field_names) {
+        let field_type = &selected_field.field.ty;
+        let arg_name = &selected_field.arg_name;
+        let conversion =
+            Conversion::of(field_type, &selected_field.options, &with_args.exact_types);
+        let set_value = generate_set_value(selected_field, &conversion, with_args);
+        downcast_fields = quote! {
+            #downcast_fields
+            #field_name => match #value.downcast::<#field_type>() {
+                Ok(#arg_name) => {
+                    let #arg_name = *#arg_name;
+                    #set_value
+                }
+                Err(#value) => return Err((self, #value)),
+            },
+        };
+        field_types.push(field_type);
+    }
+    quote! {
+        #with_fields
+        pub fn with_dyn(
+            mut self,
+            #name: &str,
+            #value: ::std::boxed::Box<dyn ::core::any::Any>,
+        ) -> Result<Self, (Self, ::std::boxed::Box<dyn ::core::any::Any>)>
+        where
+            #(#field_types: ::core::any::Any,)*
+        {
+            match #name {
+                #downcast_fields
+                _ => return Err((self, #value)),
+            }
+            Ok(self)
+        }
+    }
+}
+
+/// Generate the trait `FooWithX` of every field, like `FooWithName` for `name`, or implement the
+/// trait declared elsewhere, going through the with-constructors
+fn generate_traits<T>(
+    ast: &syn::DeriveInput,
+    selected_fields: &[SelectedField],
+    with_args: &WithArgs<T>,
+) -> proc_macro2::TokenStream {
+    let vis = &ast.vis;
+    let generics = &ast.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let self_type = self_type(ast, with_args);
+
+    let mut traits = quote!();
+    for selected_field in selected_fields {
+        let field_type = &selected_field.field.ty;
+        let arg_name = &selected_field.arg_name;
+        if selected_field.options.impl_trait.is_none() && !with_args.traits {
+            continue;
+        }
+        let constructor_name = field_method_name("with_", &selected_field.member, "");
+        let trait_path = match &selected_field.options.impl_trait {
+            Some(impl_trait) => impl_trait.to_token_stream(),
+            None => {
+                let trait_name =
+                    format_ident!("{}With{}", ast.ident, field_variant(&selected_field.member));
+                // The trait takes the generic parameters of the struct used by the field
+                if type_mentions_generics(generics, field_type) {
+                    traits = quote! {
+                        #traits
+                        #vis trait #trait_name #generics #where_clause {
+                            fn #constructor_name(self, #arg_name: #field_type) -> Self;
+                        }
+                    };
+                    quote! { #trait_name #ty_generics }
+                } else {
+                    traits = quote! {
+                        #traits
+                        #vis trait #trait_name {
+                            fn #constructor_name(self, #arg_name: #field_type) -> Self;
+                        }
+                    };
+                    quote! { #trait_name }
+                }
+            }
+        };
+
+        let conversion =
+            Conversion::of(field_type, &selected_field.options, &with_args.exact_types);
+        let set_value = generate_set_value(selected_field, &conversion, with_args);
+        traits = quote! {
+            #traits
+            #[automatically_derived]
+            impl #impl_generics #trait_path for #self_type #where_clause {
+                fn #constructor_name(mut self, #arg_name: #field_type) -> Self {
+                    #set_value
+                    self
+                }
+            }
+        };
+    }
+    traits
+}
+
+/// Generate the module `foo_fields` with a marker type for every field, like `foo_fields::a`,
+/// and implement the trait selecting the field by its marker type, like `With<foo_fields::a>`
+fn generate_selectors<T>(
+    ast: &syn::DeriveInput,
+    selected_fields: &[SelectedField],
+    with_args: &WithArgs<T>,
+    selector_trait: &Path,
+) -> proc_macro2::TokenStream {
+    let name = &ast.ident;
+    let vis = &ast.vis;
+    let (impl_generics, _, where_clause) = ast.generics.split_for_impl();
+    let self_type = self_type(ast, with_args);
+    let module_name = format_ident!("{}_fields", to_snake_case(&name.to_string()));
+
+    let mut markers = quote!();
+    let mut impls = quote!();
+    for selected_field in selected_fields {
+        let field_type = &selected_field.field.ty;
+        let arg_name = &selected_field.arg_name;
+        let marker = match &selected_field.member {
+            Member::Named(field_name) => field_name.clone(),
+            Member::Unnamed(_) => arg_name.clone(),
+        };
+        markers = quote! {
+            #markers
+            #[derive(Debug, Clone, Copy)]
+            pub struct #marker;
+        };
+
+        let conversion =
+            Conversion::of(field_type, &selected_field.options, &with_args.exact_types);
+        let set_value = generate_set_value(selected_field, &conversion, with_args);
+        impls = quote! {
+            #impls
+            #[automatically_derived]
+            impl #impl_generics #selector_trait<#module_name::#marker> for #self_type #where_clause {
+                type Value = #field_type;
+                fn with_field(mut self, #arg_name: #field_type) -> Self {
+                    #set_value
+                    self
+                }
+            }
+        };
+    }
+    quote! {
+        #[allow(non_camel_case_types)]
+        #vis mod #module_name {
+            #markers
+        }
+        #impls
+    }
+}
+
+/// Create an identifier for a local variable or argument of the generated code, resolved at
+/// the macro definition site so that it never conflicts with the field names of the struct
+fn hygienic_ident(name: &str) -> Ident {
+    Ident::new(name, Span::mixed_site())
+}
+
+/// Convert an UpperCamelCase name into snake_case, like `foo_bar` for `FooBar`
+fn to_snake_case(name: &str) -> String {
+    let mut snake_case = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                snake_case.push('_');
+            }
+            snake_case.push(c.to_ascii_lowercase());
+        } else {
+            snake_case.push(c);
+        }
+    }
+    snake_case
+}
+
+/// Whether a field can be set from its name and string value, which environment variables need
+fn parses_from_str<T>(selected_field: &SelectedField, with_args: &WithArgs<T>) -> bool {
+    let options = &selected_field.options;
+    options
+        .from_str
+        .unwrap_or(with_args.from_str || with_args.env || options.env.is_some())
+}
+
+/// Generate `Foo::with_str` setting a field from its name and string value with the FromStr
+/// trait, `Foo::with_env` setting the fields from environment variables through it,
+/// and the error enum `FooWithStrError`
+fn generate_with_str<T>(
+    ast: &syn::DeriveInput,
+    selected_fields: &[SelectedField],
+    with_args: &WithArgs<T>,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let vis = &ast.vis;
+    let error_name = format_ident!("{}WithStrError", ast.ident);
+    let field = hygienic_ident("field");
+    let value = hygienic_ident("value");
+    let prefix = hygienic_ident("prefix");
+    let var_name = hygienic_ident("var_name");
+    let lookup = hygienic_ident("lookup");
+    let str_fn = generated_method_name(
+        &with_args.str_fn,
+        "with_str",
+        "str_fn = set_str",
+        selected_fields,
+    );
+
+    let mut field_types = Vec::new();
+    let mut parsed_fields = quote!();
+    let mut env_fields = quote!();
+    let mut has_env = with_args.env;
+    for selected_field in selected_fields
+        .iter()
+        .filter(|selected_field| parses_from_str(selected_field, with_args))
+    {
+        let field_type = &selected_field.field.ty;
+        let arg_name = &selected_field.arg_name;
+        let field_name = field_name_str(&selected_field.member);
+        let conversion =
+            Conversion::of(field_type, &selected_field.options, &with_args.exact_types);
+        let set_value = generate_set_value(selected_field, &conversion, with_args);
+        parsed_fields = quote! {
+            #parsed_fields
+            #field_name => {
+                let #arg_name = <#field_type as ::core::str::FromStr>::from_str(#value)
+                    .map_err(|error| #error_name::Parse {
+                        field: #field_name,
+                        message: ::std::string::ToString::to_string(&error),
+                    })?;
+                #set_value
+            }
+        };
+        field_types.push(field_type);
+
+        // The environment variable is the prefixed field name in uppercase, unless specified
+        let env_name = match &selected_field.options.env {
+            Some(env) => {
+                has_env = true;
+                quote! { ::std::string::String::from(#env) }
+            }
+            None => {
+                let env_suffix = field_name.to_uppercase();
+                quote! {
+                    if #prefix.is_empty() {
+                        ::std::string::String::from(#env_suffix)
+                    } else {
+                        ::std::format!("{}_{}", #prefix, #env_suffix)
+                    }
+                }
+            }
+        };
+        env_fields = quote! {
+            #env_fields
+            let #var_name = #env_name;
+            if let Some(#value) = #lookup(&#var_name) {
+                let #value = #value.into_string().map_err(|_| #error_name::Parse {
+                    field: #field_name,
+                    message: ::std::format!("environment variable `{}` is not valid unicode", #var_name),
+                })?;
+                self = self.#str_fn(#field_name, &#value)?;
+            }
+        };
+    }
+
+    // The field types must be parsed with the FromStr trait
+    let where_clause = quote! {
+        where
+            #(
+                #field_types: ::core::str::FromStr,
+                <#field_types as ::core::str::FromStr>::Err: ::core::fmt::Display,
+            )*
+    };
+    let mut with_str = quote! {
+        pub fn #str_fn(mut self, #field: &str, #value: &str) -> Result<Self, #error_name>
+        #where_clause
+        {
+            match #field {
+                #parsed_fields
+                _ => return Err(#error_name::UnknownField(::std::string::ToString::to_string(#field))),
+            }
+            Ok(self)
+        }
+    };
+    if has_env {
+        let env_fn = generated_method_name(
+            &with_args.env_fn,
+            "with_env",
+            "env_fn = load_env",
+            selected_fields,
+        );
+        // The variables can also be read from another source, like `with_env_from`
+        let env_from_fn = generated_method_name(
+            &Some(format_ident!("{}_from", env_fn)),
+            "",
+            "env_fn = load_env",
+            selected_fields,
+        );
+        with_str = quote! {
+            #with_str
+            pub fn #env_fn(self, #prefix: &str) -> Result<Self, #error_name>
+            #where_clause
+            {
+                self.#env_from_fn(#prefix, |#var_name| ::std::env::var_os(#var_name))
+            }
+            pub fn #env_from_fn(
+                mut self,
+                #prefix: &str,
+                mut #lookup: impl FnMut(&str) -> Option<::std::ffi::OsString>,
+            ) -> Result<Self, #error_name>
+            #where_clause
+            {
+                #env_fields
+                Ok(self)
+            }
+        };
+    }
+    let with_str_error = quote! {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        #vis enum #error_name {
+            UnknownField(::std::string::String),
+            Parse {
+                field: &'static str,
+                message: ::std::string::String,
+            },
+        }
+
+        #[automatically_derived]
+        impl ::core::fmt::Display for #error_name {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                match self {
+                    Self::UnknownField(field) => ::core::write!(f, "unknown field `{}`", field),
+                    Self::Parse { field, message } => {
+                        ::core::write!(f, "invalid value for field `{}`: {}", field, message)
+                    }
+                }
+            }
+        }
+
+        #[automatically_derived]
+        impl ::std::error::Error for #error_name {}
+    };
+    (with_str, with_str_error)
+}
+
+/// Generate `enable_x` and `disable_x` helpers for a bool field
+fn generate_flag_constructors(
+    member: &Member,
+    track: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let enable_name = field_method_name("enable_", member, "");
+    let disable_name = field_method_name("disable_", member, "");
+    quote! {
+        pub fn #enable_name(mut self) -> Self {
+            self.#member = true;
+            #track
+            self
+        }
+        pub fn #disable_name(mut self) -> Self {
+            self.#member = false;
+            #track
+            self
+        }
+    }
+}
+
+/// Generate `with_x_if` and `with_x_opt`, which leave the field unchanged when the condition
+/// is false or the value is `None`
+fn generate_conditional_constructors(
+    member: &Member,
+    arg_name: &Ident,
+    conversion: &Conversion,
+    track: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let if_name = field_method_name("with_", member, "_if");
+    let opt_name = field_method_name("with_", member, "_opt");
+    let arg_type = conversion.arg_type();
+    let field_value = conversion.convert(arg_name);
+    let condition = hygienic_ident("condition");
+    quote! {
+        pub fn #if_name(mut self, #condition: bool, #arg_name: #arg_type) -> Self {
+            if #condition {
+                self.#member = #field_value;
+                #track
+            }
+            self
+        }
+        pub fn #opt_name(mut self, #arg_name: Option<#arg_type>) -> Self {
+            if let Some(#arg_name) = #arg_name {
+                self.#member = #field_value;
+                #track
+            }
+            self
+        }
+    }
+}
+
+/// Generate `replace_x`, which also returns the previous value of the field
+fn generate_replace_constructor(
+    member: &Member,
+    arg_name: &Ident,
+    field_type: &Type,
+    conversion: &Conversion,
+    track: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let replace_name = field_method_name("replace_", member, "");
+    let arg_type = conversion.arg_type();
+    let field_value = conversion.convert(arg_name);
+    let previous = hygienic_ident("previous");
+    quote! {
+        pub fn #replace_name(mut self, #arg_name: #arg_type) -> (Self, #field_type) {
+            let #previous = ::core::mem::replace(&mut self.#member, #field_value);
+            #track
+            (self, #previous)
+        }
+    }
+}
+
+/// Generate `reset_x`, which sets the field to its default value
+fn generate_reset_constructor(
+    member: &Member,
+    field_type: &Type,
+    default: &Option<Expr>,
+    track: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let reset_name = field_method_name("reset_", member, "");
+    match default {
+        Some(default) => quote! {
+            pub fn #reset_name(mut self) -> Self {
+                self.#member = #default;
+                #track
+                self
+            }
+        },
+        None => quote! {
+            pub fn #reset_name(mut self) -> Self
+            where
+                #field_type: ::core::default::Default,
+            {
+                self.#member = ::core::default::Default::default();
+                #track
+                self
+            }
+        },
+    }
+}
+
+/// Check if a type is the bool type
+fn is_bool(ty: &Type) -> bool {
+    matches!(ty, Type::Path(type_path) if type_path.path.is_ident("bool"))
+}
+
 /// Check if a path represents a built-in numeric type
 fn is_builtin_numeric_type(path: &Path) -> bool {
     // Get the string representation of the path
@@ -113,3 +113,899 @@ fn test_struct_with_primitive_types() {
     let foo = Foo::default().with_a(1);
     assert_eq!(foo.a, 1);
 }
+
+#[test]
+fn test_struct_switch_const_generic() {
+    #[derive(With)]
+    pub struct Buf<const N: usize> {
+        pub len: usize,
+        pub data: [u8; N],
+    }
+
+    let buf = Buf {
+        len: 2,
+        data: [0; 2],
+    }
+    .with_data([1; 4])
+    .with_len(4);
+    assert_eq!(buf.len, 4);
+    assert_eq!(buf.data, [1; 4]);
+
+    // The const generic parameter can't be switched when used by several fields
+    #[derive(With)]
+    pub struct Pair<const N: usize> {
+        pub a: [u8; N],
+        pub b: [u16; N],
+    }
+
+    let pair = Pair {
+        a: [0; 2],
+        b: [0; 2],
+    }
+    .with_a([1; 2])
+    .with_b([2; 2]);
+    assert_eq!(pair.a, [1; 2]);
+    assert_eq!(pair.b, [2; 2]);
+}
+
+#[test]
+fn test_struct_switch_generic_with_phantom_data() {
+    use std::marker::PhantomData;
+
+    #[derive(With, Default)]
+    pub struct Foo<T: Default> {
+        pub value: T,
+        pub marker: PhantomData<T>,
+    }
+
+    let foo: Foo<u8> = Foo::<String>::default().with_value(1u8);
+    assert_eq!(foo.value, 1);
+}
+
+#[test]
+fn test_struct_typestate_transition() {
+    use std::marker::PhantomData;
+
+    pub trait State {}
+    pub struct Closed;
+    pub struct Open;
+    impl State for Closed {}
+    impl State for Open {}
+
+    #[derive(With)]
+    #[with(state = S)]
+    pub struct Conn<S>
+    where
+        S: State,
+    {
+        pub addr: String,
+        pub marker: PhantomData<S>,
+    }
+
+    let conn = Conn::<Closed> {
+        addr: "a".to_string(),
+        marker: PhantomData,
+    };
+    let conn: Conn<Open> = conn.with_state::<Open>().with_addr("b");
+    assert_eq!(conn.addr, "b");
+}
+
+#[test]
+fn test_struct_keep_generic_type() {
+    #[derive(With, Default)]
+    pub struct Foo<T: Default, Z: Default> {
+        pub a: T,
+        #[with(keep_type)]
+        pub b: Z,
+    }
+
+    let foo = Foo::<u64, String>::default().with_a(1).with_b("b");
+    assert_eq!(foo.a, 1i32);
+    assert_eq!(foo.b, "b");
+
+    #[derive(With, Default)]
+    #[with(keep_type)]
+    pub struct Bar<T: Default, Z: Default> {
+        #[with(keep_type = false)]
+        pub a: T,
+        pub b: Z,
+    }
+
+    let bar = Bar::<u64, String>::default().with_a(1).with_b("b");
+    assert_eq!(bar.a, 1i32);
+    assert_eq!(bar.b, "b");
+}
+
+#[test]
+fn test_struct_conversion_policy() {
+    use std::time::Duration;
+
+    #[derive(Debug, Default, PartialEq)]
+    pub struct UserId(u64);
+
+    #[derive(With, Default)]
+    #[with(exact_types(UserId))]
+    pub struct Foo {
+        pub id: UserId,
+        #[with(into)]
+        pub count: u64,
+        #[with(exact)]
+        pub name: String,
+        #[with(into = false)]
+        pub tag: Option<&'static str>,
+        #[with(with_fn = Duration::from_secs, arg = u64)]
+        pub timeout: Duration,
+    }
+
+    let foo = Foo::default()
+        .with_id(UserId(1))
+        .with_count(2u32)
+        .with_name("a".to_string())
+        .with_tag(Some("b"))
+        .with_timeout(3);
+    assert_eq!(foo.id, UserId(1));
+    assert_eq!(foo.count, 2);
+    assert_eq!(foo.name, "a");
+    assert_eq!(foo.tag, Some("b"));
+    assert_eq!(foo.timeout, Duration::from_secs(3));
+}
+
+#[test]
+fn test_struct_wrap_field() {
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(With, Default)]
+    pub struct Node {
+        pub value: i32,
+        #[with(wrap)]
+        pub next: Option<Box<Self>>,
+        #[with(wrap)]
+        pub shared: Rc<RefCell<String>>,
+        #[with(wrap)]
+        pub label: Cow<'static, str>,
+    }
+
+    let node = Node::default()
+        .with_next(Node::default().with_value(1))
+        .with_shared("a")
+        .with_label("b");
+    assert_eq!(node.next.unwrap().value, 1);
+    assert_eq!(*node.shared.borrow(), "a");
+    assert_eq!(node.label, "b");
+}
+
+#[test]
+fn test_struct_trait_object_field() {
+    use std::sync::Arc;
+
+    pub trait Store {
+        fn get(&self) -> i32;
+    }
+    pub struct MemStore(i32);
+    impl Store for MemStore {
+        fn get(&self) -> i32 {
+            self.0
+        }
+    }
+
+    #[derive(With)]
+    pub struct Plugin<'a> {
+        pub on_event: Box<dyn Fn(i32) -> i32 + Send>,
+        pub store: Arc<dyn Store>,
+        #[with(into)]
+        pub shared: Arc<dyn Store>,
+        pub name: Box<dyn Fn() -> &'a str + 'a>,
+    }
+
+    let name = "b".to_string();
+    let plugin = Plugin {
+        on_event: Box::new(|event| event),
+        store: Arc::new(MemStore(0)),
+        shared: Arc::new(MemStore(0)),
+        name: Box::new(|| "a"),
+    }
+    .with_on_event(|event| event + 1)
+    .with_store(MemStore(2))
+    .with_name(|| name.as_str());
+    let store = plugin.store.clone();
+    let plugin = plugin.with_shared(store);
+    assert_eq!((plugin.on_event)(1), 2);
+    assert_eq!(plugin.store.get(), 2);
+    assert_eq!(plugin.shared.get(), 2);
+    assert_eq!((plugin.name)(), "b");
+}
+
+#[test]
+fn test_struct_flag_helpers() {
+    #[derive(With, Default)]
+    pub struct Foo {
+        #[with(flag)]
+        pub verbose: bool,
+        pub quiet: bool,
+    }
+
+    let foo = Foo::default().enable_verbose().with_quiet(true);
+    assert!(foo.verbose);
+    assert!(foo.quiet);
+    let foo = foo.disable_verbose();
+    assert!(!foo.verbose);
+
+    #[derive(With, Default)]
+    #[with(flag)]
+    pub struct Bar {
+        pub a: i32,
+        pub verbose: bool,
+        #[with(flag = false)]
+        pub quiet: bool,
+    }
+
+    let bar = Bar::default().with_a(1).enable_verbose().with_quiet(true);
+    assert_eq!(bar.a, 1);
+    assert!(bar.verbose);
+    assert!(bar.quiet);
+}
+
+#[test]
+fn test_struct_conditional() {
+    #[derive(With, Default)]
+    #[with(conditional)]
+    pub struct Foo<T: Default> {
+        pub a: i32,
+        pub b: String,
+        pub condition: T,
+    }
+
+    let foo = Foo::<bool>::default()
+        .with_a_if(false, 1)
+        .with_b_if(true, "b")
+        .with_condition_if(true, true);
+    assert_eq!(foo.a, 0);
+    assert_eq!(foo.b, "b");
+    assert!(foo.condition);
+
+    let foo = foo.with_a_opt(Some(2)).with_b_opt(None::<String>);
+    assert_eq!(foo.a, 2);
+    assert_eq!(foo.b, "b");
+}
+
+#[test]
+fn test_struct_replace() {
+    #[derive(With, Default)]
+    #[with(replace)]
+    pub struct Foo<T: Default> {
+        pub a: i32,
+        pub b: T,
+    }
+
+    let (foo, previous) = Foo::<String>::default().with_a(1).replace_a(2);
+    assert_eq!(previous, 1);
+    assert_eq!(foo.a, 2);
+
+    let (foo, previous) = foo.replace_b("b");
+    assert_eq!(previous, "");
+    assert_eq!(foo.b, "b");
+}
+
+#[test]
+fn test_struct_reset() {
+    #[derive(With, Default)]
+    #[with(reset)]
+    pub struct Foo<T> {
+        pub a: u16,
+        #[with(default = String::from("localhost"))]
+        pub b: String,
+        pub c: T,
+    }
+
+    let foo = Foo::<i32>::default()
+        .with_a(1)
+        .with_b("b")
+        .with_c(2)
+        .reset_a()
+        .reset_b()
+        .reset_c();
+    assert_eq!(foo.a, 0);
+    assert_eq!(foo.b, "localhost");
+    assert_eq!(foo.c, 0);
+}
+
+#[test]
+fn test_struct_patch() {
+    #[derive(With, Default, Debug, PartialEq)]
+    #[with(patch)]
+    pub struct Foo<T: Default> {
+        pub a: i32,
+        pub b: String,
+        pub c: T,
+        #[with(wrap)]
+        pub d: Option<u8>,
+        pub r#type: String,
+    }
+
+    let patch = FooPatch::default()
+        .with_b("b")
+        .with_c(true)
+        .with_d(1)
+        .with_type("t");
+    assert_eq!(patch.a, None);
+    assert_eq!(patch.b, Some("b".to_string()));
+
+    let foo = Foo::<bool>::default().with_a(1).apply(patch);
+    assert_eq!(
+        foo,
+        Foo {
+            a: 1,
+            b: "b".to_string(),
+            c: true,
+            d: Some(1),
+            r#type: "t".to_string(),
+        }
+    );
+
+    #[derive(With, Default)]
+    #[with(a, patch)]
+    pub struct Bar<T> {
+        pub a: i32,
+        pub b: T,
+    }
+
+    let bar = Bar::<u8>::default().apply(BarPatch {
+        a: Some(1),
+        ..Default::default()
+    });
+    assert_eq!(bar.a, 1);
+    assert_eq!(bar.b, 0);
+}
+
+#[test]
+fn test_struct_diff() {
+    #[derive(With, Default, Clone, Debug, PartialEq)]
+    #[with(diff, patch_attr(derive(Debug, Clone)))]
+    pub struct Foo<T> {
+        pub a: i32,
+        pub b: String,
+        pub c: T,
+    }
+
+    let a = Foo::<u8>::default().with_a(1).with_b("b");
+    let b = a.clone().with_b("c").with_c(2);
+
+    let patch = a.diff(&b);
+    assert_eq!(patch.a, None);
+    assert_eq!(patch.b, Some("c".to_string()));
+    assert_eq!(patch.c, Some(2));
+    assert_eq!(
+        format!("{:?}", patch.clone()),
+        r#"FooPatch { a: None, b: Some("c"), c: Some(2) }"#
+    );
+    assert_eq!(a.clone().apply(patch), b);
+
+    let patch = a.diff(&a);
+    assert_eq!(patch.a, None);
+    assert_eq!(patch.b, None);
+    assert_eq!(patch.c, None);
+}
+
+#[test]
+fn test_struct_tracker() {
+    #[derive(With, Default)]
+    #[with(conditional, flag, replace, reset)]
+    pub struct Foo<T> {
+        pub a: i32,
+        pub foo_bar: String,
+        pub c: T,
+        pub d: bool,
+        pub r#type: bool,
+        #[with(tracker)]
+        pub changes: Vec<FooField>,
+    }
+
+    let mut foo = Foo::<u8>::default()
+        .with_a(1)
+        .with_c(true)
+        .with_foo_bar_if(false, "b")
+        .with_a(2);
+    assert_eq!(foo.changed_fields(), &[FooField::A, FooField::C]);
+    assert!(foo.is_changed(FooField::A));
+    assert!(!foo.is_changed(FooField::FooBar));
+
+    foo.clear_changes();
+    let foo = foo.with_foo_bar_opt(Some("b"));
+    assert_eq!(foo.foo_bar, "b");
+    assert!(foo.c);
+    assert_eq!(foo.changed_fields(), &[FooField::FooBar]);
+
+    let mut foo = foo.enable_d();
+    foo.clear_changes();
+    let (foo, previous) = foo.disable_d().replace_a(3);
+    assert_eq!(previous, 2);
+    let foo = foo.reset_foo_bar();
+    assert_eq!(foo.foo_bar, "");
+    assert_eq!(
+        foo.changed_fields(),
+        &[FooField::D, FooField::A, FooField::FooBar]
+    );
+
+    let (foo, previous) = foo
+        .enable_type()
+        .with_type_if(true, false)
+        .replace_type(true);
+    assert!(!previous);
+    let foo = foo.with_type_opt(None::<bool>).disable_type().reset_type();
+    assert!(!foo.r#type);
+    assert!(foo.is_changed(FooField::Type));
+}
+
+#[test]
+fn test_struct_field_enum() {
+    #[derive(With, Default, Debug, PartialEq)]
+    #[with(field_enum)]
+    pub struct Foo<T> {
+        pub a: i32,
+        pub foo_bar: String,
+        pub c: T,
+    }
+
+    assert_eq!(FooField::ALL, &[FooField::A, FooField::FooBar, FooField::C]);
+    assert_eq!(FooField::FooBar.name(), "foo_bar");
+
+    let updates = vec![
+        FooFieldValue::A(1),
+        FooFieldValue::FooBar("b".to_string()),
+        FooFieldValue::C(2),
+    ];
+    assert_eq!(updates[1].field(), FooField::FooBar);
+    let foo = updates
+        .into_iter()
+        .fold(Foo::<u8>::default(), Foo::with_field_value);
+    assert_eq!(
+        foo,
+        Foo {
+            a: 1,
+            foo_bar: "b".to_string(),
+            c: 2,
+        }
+    );
+
+    #[derive(With, Default)]
+    #[with(a, field_enum)]
+    pub struct Bar<T> {
+        pub a: i32,
+        pub b: T,
+    }
+
+    assert_eq!(BarFieldValue::<u8>::A(1).field(), BarField::A);
+    let bar = Bar::<u8>::default().with_field_value(BarFieldValue::A(1));
+    assert_eq!(bar.a, 1);
+    assert_eq!(bar.b, 0);
+
+    #[derive(With, Default)]
+    #[with(field_enum, field_value_fn = set_field)]
+    pub struct Baz {
+        pub field_value: i32,
+    }
+
+    let baz = Baz::default().set_field(BazFieldValue::FieldValue(1));
+    assert_eq!(baz.field_value, 1);
+    assert_eq!(baz.with_field_value(2).field_value, 2);
+}
+
+#[test]
+fn test_struct_from_str() {
+    #[derive(With, Default)]
+    #[with(from_str)]
+    pub struct Foo<T> {
+        pub a: i32,
+        pub b: String,
+        pub c: T,
+        #[with(from_str = false)]
+        pub d: Vec<u8>,
+    }
+
+    let foo = Foo::<bool>::default()
+        .with_str("a", "1")
+        .and_then(|foo| foo.with_str("c", "true"))
+        .unwrap();
+    assert_eq!(foo.a, 1);
+    assert!(foo.c);
+    assert!(foo.b.is_empty());
+    assert!(foo.d.is_empty());
+
+    let error = Foo::<bool>::default().with_str("d", "1").err().unwrap();
+    assert_eq!(error, FooWithStrError::UnknownField("d".to_string()));
+    assert_eq!(error.to_string(), "unknown field `d`");
+
+    let error = Foo::<bool>::default().with_str("a", "x").err().unwrap();
+    assert!(matches!(error, FooWithStrError::Parse { field: "a", .. }));
+
+    #[derive(With, Default)]
+    #[with(from_str = true, str_fn = set_str)]
+    pub struct Bar {
+        pub str: String,
+    }
+
+    let bar = Bar::default().set_str("str", "a").unwrap();
+    assert_eq!(bar.str, "a");
+    assert_eq!(bar.with_str("b").str, "b");
+}
+
+#[test]
+fn test_struct_env() {
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+
+    #[derive(With, Default)]
+    #[with(env)]
+    pub struct Foo {
+        pub a: i32,
+        pub b: String,
+        #[with(env = "DERIVE_WITH_TEST_PORT")]
+        pub port: u16,
+    }
+
+    // Read the variables from a map, as mutating the environment races with the other tests
+    let vars = HashMap::from([("FOO_A", "1"), ("DERIVE_WITH_TEST_PORT", "8080")]);
+    let lookup = |vars: &HashMap<&str, &str>, name: &str| vars.get(name).map(OsString::from);
+    let foo = Foo::default()
+        .with_b("b")
+        .with_env_from("FOO", |name| lookup(&vars, name))
+        .unwrap();
+    assert_eq!(foo.a, 1);
+    assert_eq!(foo.b, "b");
+    assert_eq!(foo.port, 8080);
+
+    let vars = HashMap::from([("FOO_A", "x")]);
+    let error = Foo::default()
+        .with_env_from("FOO", |name| lookup(&vars, name))
+        .err()
+        .unwrap();
+    assert!(matches!(error, FooWithStrError::Parse { field: "a", .. }));
+
+    // Unset variables leave the fields unchanged
+    let foo = Foo::default().with_env("DERIVE_WITH_TEST_UNSET").unwrap();
+    assert_eq!(foo.a, 0);
+
+    #[derive(With, Default)]
+    #[with(env = true, env_fn = load_env)]
+    pub struct Bar {
+        pub env: String,
+    }
+
+    let vars = HashMap::from([("BAR_ENV", "prod")]);
+    let bar = Bar::default()
+        .load_env_from("BAR", |name| lookup(&vars, name))
+        .unwrap();
+    assert_eq!(bar.env, "prod");
+    assert_eq!(bar.with_env("dev").env, "dev");
+}
+
+#[test]
+fn test_struct_with_dyn() {
+    #[derive(With, Default)]
+    #[with(with_dyn)]
+    pub struct Foo<T> {
+        pub a: i32,
+        pub b: String,
+        pub c: T,
+    }
+
+    assert_eq!(Foo::<u8>::WITH_FIELDS, &["a", "b", "c"]);
+
+    let foo = Foo::<u8>::default()
+        .with_dyn("a", Box::new(1))
+        .and_then(|foo| foo.with_dyn("c", Box::new(2u8)))
+        .ok()
+        .unwrap();
+    assert_eq!(foo.a, 1);
+    assert_eq!(foo.b, "");
+    assert_eq!(foo.c, 2);
+
+    let (foo, value) = foo.with_dyn("b", Box::new(1)).err().unwrap();
+    assert_eq!(foo.b, "");
+    assert_eq!(value.downcast_ref::<i32>(), Some(&1));
+    assert!(foo.with_dyn("d", Box::new(1)).is_err());
+
+    #[derive(With, Default)]
+    #[with(b)]
+    pub struct Bar {
+        pub a: i32,
+        pub b: String,
+    }
+
+    assert_eq!(Bar::WITH_FIELDS, &["b"]);
+    let bar = Bar::default().with_b("b");
+    assert_eq!(bar.a, 0);
+    assert_eq!(bar.b, "b");
+}
+
+#[test]
+fn test_struct_traits() {
+    #[derive(With, Default)]
+    #[with(traits)]
+    pub struct Foo<T> {
+        pub name: String,
+        pub b: T,
+        pub r#type: u8,
+    }
+
+    #[derive(With, Default)]
+    #[with(traits)]
+    pub struct Bar {
+        #[with(impl_trait = FooWithName)]
+        pub name: String,
+        pub count: i32,
+    }
+
+    fn rename<T: FooWithName>(t: T) -> T {
+        t.with_name("renamed".to_string())
+    }
+
+    fn set_b<T: FooWithB<u8>>(t: T) -> T {
+        t.with_b(2)
+    }
+
+    fn set_type<T: FooWithType>(t: T) -> T {
+        t.with_type(3)
+    }
+
+    let foo = rename(set_type(Foo::<u8>::default().with_b(1)));
+    assert_eq!(foo.name, "renamed");
+    assert_eq!(foo.b, 1);
+    assert_eq!(foo.r#type, 3);
+    assert_eq!(set_b(foo).b, 2);
+
+    fn count<T: BarWithCount>(t: T) -> T {
+        t.with_count(1)
+    }
+
+    let bar = count(rename(Bar::default()));
+    assert_eq!(bar.name, "renamed");
+    assert_eq!(bar.count, 1);
+}
+
+#[test]
+fn test_struct_selectors() {
+    derive_with::with_selectors!();
+
+    #[derive(With, Default)]
+    #[with(selectors)]
+    pub struct FooBar<T> {
+        pub a: i32,
+        pub b: String,
+        pub c: T,
+    }
+
+    fn update<S: With<F>, F>(s: S, value: S::Value) -> S {
+        s.with_field(value)
+    }
+
+    let foo = FooBar::<u8>::default()
+        .with::<foo_bar_fields::a>(1)
+        .with::<foo_bar_fields::c>(2);
+    let foo = update::<_, foo_bar_fields::b>(foo, "b".to_string());
+    assert_eq!(foo.a, 1);
+    assert_eq!(foo.b, "b");
+    assert_eq!(foo.c, 2);
+}
+
+#[test]
+fn test_struct_extension_trait() {
+    #[derive(With, Default)]
+    #[with(trait = FooWith, conditional)]
+    pub struct Foo<T> {
+        pub a: i32,
+        pub timeout: u64,
+        pub c: T,
+    }
+
+    impl<T> Foo<T> {
+        pub fn with_timeout(mut self, timeout: u64) -> Self {
+            self.timeout = timeout.min(60);
+            self
+        }
+    }
+
+    let foo = Foo::<u8>::default()
+        .with_a(1)
+        .with_timeout(120)
+        .with_c(true)
+        .with_a_if(false, 2);
+    assert_eq!(foo.a, 1);
+    assert_eq!(foo.timeout, 60);
+    assert!(foo.c);
+
+    let foo = FooWith::with_timeout(foo, 120);
+    assert_eq!(foo.timeout, 120);
+
+    #[derive(With, Default)]
+    #[with(trait = BarWith, traits)]
+    pub struct Bar {
+        pub a: i32,
+    }
+
+    fn set_a<T: BarWithA>(t: T) -> T {
+        t.with_a(2)
+    }
+
+    let bar = BarWith::with_a(Bar::default(), 1);
+    assert_eq!(bar.a, 1);
+    assert_eq!(set_a(bar).a, 2);
+}
+
+#[test]
+fn test_struct_remote() {
+    mod other_crate {
+        #[derive(Default)]
+        pub struct Config<T> {
+            pub name: String,
+            pub port: u16,
+            pub extra: T,
+            secret: bool,
+        }
+
+        impl<T> Config<T> {
+            pub fn secret(&self) -> bool {
+                self.secret
+            }
+        }
+
+        #[derive(Default)]
+        pub struct Endpoint {
+            pub host: String,
+            pub port: u16,
+        }
+    }
+
+    #[allow(dead_code)]
+    #[derive(With)]
+    #[with(remote = "other_crate::Config", conditional)]
+    pub struct ConfigDef<T> {
+        pub name: String,
+        pub port: u16,
+        pub extra: T,
+    }
+
+    let config = other_crate::Config::<u8>::default()
+        .with_name("name")
+        .with_port(8080)
+        .with_extra(1)
+        .with_port_if(false, 80);
+    assert_eq!(config.name, "name");
+    assert_eq!(config.port, 8080);
+    assert_eq!(config.extra, 1);
+    assert!(!config.secret());
+
+    #[allow(dead_code)]
+    #[derive(With)]
+    #[with(remote = "other_crate::Endpoint", exhaustive)]
+    pub struct EndpointDef {
+        pub host: String,
+        pub port: u16,
+    }
+
+    let endpoint = other_crate::Endpoint::default()
+        .with_host("localhost")
+        .with_port(80);
+    assert_eq!(endpoint.host, "localhost");
+    assert_eq!(endpoint.port, 80);
+}
+
+#[test]
+fn test_struct_with_macro() {
+    #[derive(With, Default)]
+    pub struct Foo<T> {
+        pub a: i32,
+        pub b: String,
+        pub c: T,
+    }
+
+    let c = true;
+    let foo = derive_with::with!(Foo::<u8>::default(), a: 1, b: "b", c,);
+    assert_eq!(foo.a, 1);
+    assert_eq!(foo.b, "b");
+    assert!(foo.c);
+
+    let boxed = Box::new(foo);
+    let foo = derive_with::with!(*boxed, a: 2);
+    assert_eq!(foo.a, 2);
+    assert_eq!(foo.b, "b");
+}
+
+#[test]
+fn test_struct_nested() {
+    #[derive(With, Default)]
+    pub struct TlsConfig {
+        pub cert: String,
+        pub key: String,
+    }
+
+    #[derive(With, Default)]
+    #[with(patch)]
+    pub struct Server {
+        pub port: u16,
+        #[with(nested)]
+        pub tls: TlsConfig,
+    }
+
+    let server = Server::default()
+        .with_port(443)
+        .with_tls(|tls| tls.with_cert("cert").with_key("key"));
+    assert_eq!(server.port, 443);
+    assert_eq!(server.tls.cert, "cert");
+    assert_eq!(server.tls.key, "key");
+
+    let server = server.apply(ServerPatch::default().with_tls(TlsConfig::default()));
+    assert_eq!(server.tls.cert, "");
+}
+
+#[test]
+fn test_struct_flatten() {
+    #[derive(With, Default)]
+    #[with(flattenable, flag)]
+    pub struct CommonOpts {
+        pub verbose: bool,
+        pub config: String,
+        #[with(wrap)]
+        pub parent: Option<Box<Self>>,
+    }
+
+    #[derive(With, Default)]
+    pub struct Outer<T> {
+        pub a: i32,
+        pub b: T,
+        #[with(flatten)]
+        pub common: CommonOpts,
+    }
+
+    let outer = Outer::<u8>::default()
+        .with_a(1)
+        .with_verbose(true)
+        .with_config("config")
+        .with_b(2);
+    assert_eq!(outer.a, 1);
+    assert_eq!(outer.b, 2);
+    assert!(outer.common.verbose);
+    assert_eq!(outer.common.config, "config");
+
+    let outer = outer.disable_verbose();
+    assert!(!outer.common.verbose);
+
+    let outer = outer.with_parent(CommonOpts::default().with_config("parent"));
+    assert_eq!(outer.common.parent.unwrap().config, "parent");
+}
+
+#[test]
+fn test_struct_option_named_field() {
+    #[derive(With, Default)]
+    #[with(patch)]
+    pub struct Foo {
+        pub patch: u8,
+        pub other: u8,
+    }
+
+    // Would conflict with the generated items if `patch` enabled the patch struct
+    impl Foo {
+        pub fn with_other(self, other: u8) -> Self {
+            Self { other, ..self }
+        }
+    }
+    #[allow(dead_code)]
+    pub struct FooPatch;
+
+    let foo = Foo::default().with_patch(1).with_other(2);
+    assert_eq!(foo.patch, 1);
+    assert_eq!(foo.other, 2);
+
+    #[derive(With, Default)]
+    #[with(patch = true)]
+    pub struct Bar {
+        pub patch: u8,
+        pub other: u8,
+    }
+
+    let bar = Bar::default().apply(BarPatch::default().with_patch(1).with_other(2));
+    assert_eq!(bar.patch, 1);
+    assert_eq!(bar.other, 2);
+}
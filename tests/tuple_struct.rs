@@ -81,3 +81,379 @@ fn test_tuple_struct_with_primitive_types() {
     let bar = Bar::default().with_0(1);
     assert_eq!(bar.0, 1);
 }
+
+#[test]
+fn test_tuple_struct_switch_const_generic() {
+    #[derive(With)]
+    pub struct Buf<const N: usize>(usize, [u8; N]);
+
+    let buf = Buf(2, [0; 2]).with_1([1; 4]).with_0(4);
+    assert_eq!(buf.0, 4);
+    assert_eq!(buf.1, [1; 4]);
+}
+
+#[test]
+fn test_tuple_struct_switch_generic_with_phantom_data() {
+    use std::marker::PhantomData;
+
+    #[derive(With, Default)]
+    pub struct Bar<T: Default>(T, PhantomData<T>);
+
+    let bar: Bar<u8> = Bar::<String>::default().with_0(1u8);
+    assert_eq!(bar.0, 1);
+}
+
+#[test]
+fn test_tuple_struct_typestate_transition() {
+    use std::marker::PhantomData;
+
+    pub trait State {}
+    pub struct Closed;
+    pub struct Open;
+    impl State for Closed {}
+    impl State for Open {}
+
+    #[derive(With)]
+    #[with(state = S)]
+    pub struct Conn<S: State>(String, PhantomData<S>);
+
+    let conn = Conn::<Closed>("a".to_string(), PhantomData);
+    let conn: Conn<Open> = conn.with_state::<Open>().with_0("b");
+    assert_eq!(conn.0, "b");
+}
+
+#[test]
+fn test_tuple_struct_keep_generic_type() {
+    #[derive(With, Default)]
+    pub struct Bar<T: Default, Z: Default>(T, #[with(keep_type)] Z);
+
+    let bar = Bar::<u64, String>::default().with_0(1).with_1("b");
+    assert_eq!(bar.0, 1i32);
+    assert_eq!(bar.1, "b");
+
+    #[derive(With, Default)]
+    #[with(keep_type)]
+    pub struct Baz<T: Default>(T);
+
+    let baz = Baz::<u64>::default().with_0(1u32);
+    assert_eq!(baz.0, 1u64);
+}
+
+#[test]
+fn test_tuple_struct_conversion_policy() {
+    use std::time::Duration;
+
+    #[derive(With, Default)]
+    pub struct Bar(
+        #[with(into)] u64,
+        #[with(with_fn = Duration::from_millis, arg = u64)] Duration,
+    );
+
+    let bar = Bar::default().with_0(1u32).with_1(2);
+    assert_eq!(bar.0, 1);
+    assert_eq!(bar.1, Duration::from_millis(2));
+}
+
+#[test]
+fn test_tuple_struct_wrap_field() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(With, Default)]
+    pub struct Bar(#[with(wrap)] Arc<Mutex<u32>>, #[with(wrap)] Option<String>);
+
+    let bar = Bar::default().with_0(1).with_1("a");
+    assert_eq!(*bar.0.lock().unwrap(), 1);
+    assert_eq!(bar.1.as_deref(), Some("a"));
+}
+
+#[test]
+fn test_tuple_struct_trait_object_field() {
+    use std::rc::Rc;
+
+    #[derive(With)]
+    pub struct Bar(
+        Box<dyn Fn(i32) -> i32>,
+        #[with(wrap)] Option<Box<dyn Fn() -> i32>>,
+        #[with(into)] Rc<dyn Fn(i32) -> i32>,
+        Rc<dyn Fn(i32) -> i32>,
+    );
+
+    let shared: Rc<dyn Fn(i32) -> i32> = Rc::new(|x| x + 1);
+    let bar = Bar(Box::new(|x| x), None, Rc::new(|x| x), Rc::new(|x| x))
+        .with_0(|x| x * 2)
+        .with_1(|| 3)
+        .with_2(shared.clone())
+        .with_3(|x| x * 3);
+    assert_eq!((bar.0)(2), 4);
+    assert_eq!((bar.1.unwrap())(), 3);
+    assert_eq!((bar.2)(2), 3);
+    assert_eq!((bar.3)(2), 6);
+}
+
+#[test]
+fn test_tuple_struct_flag_helpers() {
+    #[derive(With, Default)]
+    #[with(flag)]
+    pub struct Bar(i32, bool);
+
+    let bar = Bar::default().with_0(1).enable_1();
+    assert_eq!(bar.0, 1);
+    assert!(bar.1);
+    let bar = bar.disable_1();
+    assert!(!bar.1);
+}
+
+#[test]
+fn test_tuple_struct_conditional() {
+    #[derive(With, Default)]
+    pub struct Bar(#[with(conditional)] i32, String);
+
+    let bar = Bar::default().with_0_if(true, 1).with_0_opt(None);
+    assert_eq!(bar.0, 1);
+    let bar = bar.with_0_if(false, 2).with_0_opt(Some(3));
+    assert_eq!(bar.0, 3);
+    assert_eq!(bar.1, "");
+}
+
+#[test]
+fn test_tuple_struct_replace() {
+    #[derive(With, Default)]
+    pub struct Bar(i32, #[with(replace)] String);
+
+    let (bar, previous) = Bar::default().with_1("a").replace_1("b");
+    assert_eq!(previous, "a");
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_reset() {
+    #[derive(With, Default)]
+    pub struct Bar(#[with(reset)] u16, #[with(default = 8080)] u16);
+
+    let bar = Bar::default().with_0(1).with_1(2).reset_0().reset_1();
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1, 8080);
+}
+
+#[test]
+fn test_tuple_struct_patch() {
+    #[derive(With, Default)]
+    #[with(1, patch)]
+    pub struct Bar<'a>(i32, &'a str);
+
+    let patch = BarPatch::default().with_1("b");
+    assert_eq!(patch.0, Some("b"));
+
+    let bar = Bar::default().apply(patch);
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_diff() {
+    #[derive(With, Default, Clone, Debug, PartialEq)]
+    #[with(0, diff, patch_attr(derive(Debug)))]
+    pub struct Bar(i32, String);
+
+    let a = Bar(1, "a".to_string());
+    let b = Bar(2, "b".to_string());
+
+    let patch = a.diff(&b);
+    assert_eq!(patch.0, Some(2));
+    assert_eq!(format!("{:?}", patch), "BarPatch(Some(2))");
+    assert_eq!(a.apply(patch), Bar(2, "a".to_string()));
+}
+
+#[test]
+fn test_tuple_struct_tracker() {
+    #[derive(With, Default)]
+    #[with(flag, replace, reset)]
+    pub struct Bar(i32, String, bool, #[with(tracker)] Vec<BarField>);
+
+    let bar = Bar::default().with_1("b");
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.changed_fields(), &[BarField::Field1]);
+    assert!(!bar.is_changed(BarField::Field0));
+
+    let (bar, previous) = bar.enable_2().replace_0(1);
+    assert_eq!(previous, 0);
+    let bar = bar.reset_1();
+    assert_eq!(bar.1, "");
+    assert_eq!(
+        bar.changed_fields(),
+        &[BarField::Field1, BarField::Field2, BarField::Field0]
+    );
+}
+
+#[test]
+fn test_tuple_struct_field_enum() {
+    #[derive(With, Default)]
+    #[with(field_enum)]
+    pub struct Bar(i32, String);
+
+    assert_eq!(BarField::ALL, &[BarField::Field0, BarField::Field1]);
+    assert_eq!(BarField::Field1.name(), "1");
+
+    let bar = Bar::default()
+        .with_field_value(BarFieldValue::Field0(1))
+        .with_field_value(BarFieldValue::Field1("b".to_string()));
+    assert_eq!(bar.0, 1);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_from_str() {
+    #[derive(With, Default)]
+    #[with(from_str)]
+    pub struct Bar(i32, String);
+
+    let bar = Bar::default().with_str("1", "b").unwrap();
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1, "b");
+
+    let error = Bar::default().with_str("0", "b").err().unwrap();
+    assert!(error.to_string().starts_with("invalid value for field `0`"));
+}
+
+#[test]
+fn test_tuple_struct_env() {
+    #[derive(With, Default)]
+    pub struct Bar(i32, #[with(env = "DERIVE_WITH_TEST_BAR_NAME")] String);
+
+    // Read the variable from a closure, as mutating the environment races with the other tests
+    let bar = Bar::default()
+        .with_env_from("BAR", |name| {
+            (name == "DERIVE_WITH_TEST_BAR_NAME").then(|| "b".into())
+        })
+        .unwrap();
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_with_dyn() {
+    #[derive(With, Default)]
+    #[with(with_dyn)]
+    pub struct Bar(i32, String);
+
+    assert_eq!(Bar::WITH_FIELDS, &["0", "1"]);
+
+    let bar = Bar::default()
+        .with_dyn("1", Box::new("b".to_string()))
+        .ok()
+        .unwrap();
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_traits() {
+    #[derive(With, Default)]
+    #[with(1, traits)]
+    pub struct Bar(i32, String);
+
+    fn set<T: BarWithField1>(t: T) -> T {
+        t.with_1("b".to_string())
+    }
+
+    let bar = set(Bar::default());
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_selectors() {
+    derive_with::with_selectors!();
+
+    #[derive(With, Default)]
+    #[with(selectors)]
+    pub struct Bar(i32, String);
+
+    let bar = Bar::default().with::<bar_fields::field_1>("b".to_string());
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_extension_trait() {
+    mod bar {
+        use derive_with::With;
+
+        #[derive(With, Default)]
+        #[with(trait = BarWith)]
+        pub struct Bar(pub i32, pub String);
+    }
+
+    use bar::BarWith;
+
+    let bar = bar::Bar::default().with_0(1).with_1("b");
+    assert_eq!(bar.0, 1);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_remote() {
+    mod other_crate {
+        #[derive(Default)]
+        pub struct Bar(pub i32, pub String);
+    }
+
+    #[allow(dead_code)]
+    #[derive(With)]
+    #[with(remote = "other_crate::Bar", trait = RemoteBarWith, exhaustive)]
+    pub struct BarDef(i32, String);
+
+    let bar = other_crate::Bar::default().with_0(1).with_1("b");
+    assert_eq!(bar.0, 1);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_with_macro() {
+    #[derive(With, Default)]
+    pub struct Bar(i32, String);
+
+    let bar = derive_with::with!(Bar::default(), 0: 1, 1: "b");
+    assert_eq!(bar.0, 1);
+    assert_eq!(bar.1, "b");
+
+    let boxed = Box::new(bar);
+    let bar = derive_with::with!(*boxed, 0: 2);
+    assert_eq!(bar.0, 2);
+    assert_eq!(bar.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_nested() {
+    #[derive(With, Default)]
+    pub struct Inner(i32, String);
+
+    #[derive(With, Default)]
+    pub struct Bar(i32, #[with(nested)] Inner);
+
+    let bar = Bar::default().with_1(|inner| inner.with_0(1).with_1("b"));
+    assert_eq!(bar.0, 0);
+    assert_eq!(bar.1.0, 1);
+    assert_eq!(bar.1.1, "b");
+}
+
+#[test]
+fn test_tuple_struct_flatten() {
+    #[derive(With, Default)]
+    #[with(flattenable)]
+    pub struct Inner(i32, String);
+
+    #[derive(With, Default)]
+    #[with(0)]
+    pub struct Bar(bool, #[with(flatten)] Inner);
+
+    // The with-constructors of the struct take precedence over the flattened ones
+    let bar = Bar::default().with_0(true).with_1("b");
+    assert!(bar.0);
+    assert_eq!(bar.1.0, 0);
+    assert_eq!(bar.1.1, "b");
+
+    let bar = InnerWithFlatten::with_0(bar, 1);
+    assert_eq!(bar.1.0, 1);
+}